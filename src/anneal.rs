@@ -1,9 +1,27 @@
 use super::{EvalModel, EvalScores, Layout, TextStats};
-use rand::SeedableRng;
+use super::{layout_from_str, layout_to_str};
+use rand::{Rng, SeedableRng};
 use rand::rngs::SmallRng;
-use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
 use std::io;
 
+// Snapshot of an annealing run that can be saved and resumed later. The
+// random number generator is reseeded every time Anneal::next returns, so
+// its state is just the last seed. Layouts are stored as text.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AnnealState {
+    noise: f64,
+    noise_step: f64,
+    noise_floor: f64,
+    precision: f64,
+    cur_layout: String,
+    best_layout: String,
+    real_layout: String,
+    steps: u64,
+    steps_per_iter: u64,
+    seed: u64,
+}
+
 pub struct Anneal<'a, M>
 where M: EvalModel<'a>
 {
@@ -14,10 +32,14 @@ where M: EvalModel<'a>
     noise_floor: f64,
     precision: f64,
     cur_layout: Layout,
+    cur_scores: M::Scores,
+    best_layout: Layout,
     best_scores: M::Scores,
+    real_layout: Layout,
     real_scores: M::Scores,
     steps: u64,
     steps_per_iter: u64,
+    seed: u64,
     rng: SmallRng,
 }
 
@@ -25,13 +47,16 @@ impl<'a, M> Anneal<'a, M>
 where M: EvalModel<'a>
 {
     pub fn new(model: &'a M, text: &'a TextStats,
-               layout: Layout, shuffle: bool, steps_per_iter: u64) -> Self {
-        let mut rng = SmallRng::from_entropy();
-        let mut layout = layout;
+               layout: Layout, shuffle: bool, steps_per_iter: u64,
+               seed: u64) -> Self {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let layout = if shuffle {
+            model.shuffle(&mut rng, &layout)
+        } else {
+            layout
+        };
 
-        if shuffle {
-            layout.shuffle(&mut rng);
-        }
+        let best_scores = model.eval_layout(&layout, text, 0.0, false);
 
         Anneal {
             model, text,
@@ -40,14 +65,70 @@ where M: EvalModel<'a>
             noise_floor: 0.001,
             precision: 0.0,
             cur_layout: layout,
-            best_scores: model.eval_layout(&layout, text, 0.0, false),
+            cur_scores: best_scores.clone(),
+            best_layout: layout,
+            best_scores,
+            real_layout: layout,
             real_scores: model.eval_layout(&layout, text, 1.0, false),
             steps: 0,
             steps_per_iter,
+            seed,
             rng,
         }
     }
 
+    // Continue an annealing run from a saved state
+    pub fn resume(model: &'a M, text: &'a TextStats, state: &AnnealState)
+        -> Result<Self, String>
+    {
+        let cur_layout = layout_from_str(&state.cur_layout)?;
+        let best_layout = layout_from_str(&state.best_layout)?;
+        let real_layout = layout_from_str(&state.real_layout)?;
+
+        Ok(Anneal {
+            model, text,
+            noise: state.noise,
+            noise_step: state.noise_step,
+            noise_floor: state.noise_floor,
+            precision: state.precision,
+            cur_layout,
+            cur_scores: model.eval_layout(&cur_layout, text, state.precision,
+                                          false),
+            best_layout,
+            best_scores: model.eval_layout(&best_layout, text, state.precision,
+                                           false),
+            real_layout,
+            real_scores: model.eval_layout(&real_layout, text, 1.0, false),
+            steps: state.steps,
+            steps_per_iter: state.steps_per_iter,
+            seed: state.seed,
+            rng: SmallRng::seed_from_u64(state.seed),
+        })
+    }
+
+    // Save the state between calls to next
+    pub fn checkpoint(&self) -> AnnealState {
+        AnnealState {
+            noise: self.noise,
+            noise_step: self.noise_step,
+            noise_floor: self.noise_floor,
+            precision: self.precision,
+            cur_layout: layout_to_str(&self.cur_layout),
+            best_layout: layout_to_str(&self.best_layout),
+            real_layout: layout_to_str(&self.real_layout),
+            steps: self.steps,
+            steps_per_iter: self.steps_per_iter,
+            seed: self.seed,
+        }
+    }
+
+    // Scores of the best layout so far, evaluated with full precision. This
+    // is what the last call to next returned, or the starting point of a new
+    // or resumed run.
+    pub fn scores(&self) -> M::Scores {
+        self.real_scores.clone()
+    }
+
     pub fn write_stats<W>(&self, w: &mut W) -> io::Result<()>
     where W: io::Write {
         writeln!(w, "step:{} nois:{:.4} dNoi:{:.10} prec:{:.3} best:{:6.4}",
@@ -59,10 +140,18 @@ where M: EvalModel<'a>
         self.precision += (1.0 - self.precision) * d;
 
         // Reevaluate the best known layout with updated precision
-        self.best_scores = self.model.eval_layout(&self.best_scores.layout(),
+        self.best_layout = self.best_scores.layout();
+        self.best_scores = self.model.eval_layout(&self.best_layout,
                                                   self.text, self.precision,
                                                   false);
     }
+
+    // Reseed the random number generator, so that a checkpoint taken
+    // before the next step can reproduce its state
+    fn reseed(&mut self) {
+        self.seed = self.rng.gen();
+        self.rng = SmallRng::seed_from_u64(self.seed);
+    }
 }
 
 // Iterator interface for simulated annealing. Each call to next will return
@@ -94,19 +183,21 @@ where M: EvalModel<'a>
                 }
 
                 self.update_precision(self.noise_step*0.1);
+                self.reseed();
 
                 return Some(self.real_scores.clone());
             }
             self.steps += 1;
 
             let layout = self.model.neighbor(&mut self.rng, &self.cur_layout);
-            let scores = self.model.eval_layout(&layout, self.text,
-                                                self.precision, false);
+            let scores = self.model.eval_layout_delta(&self.cur_scores, &layout,
+                                                      self.text, self.precision);
 
             if scores.total() > self.best_scores.total() + 100.0*self.noise {
                 // We're stuck in a local optimum with little hope of
                 // getting back out. Reset to last know global optimum
                 self.cur_layout = self.best_scores.layout();
+                self.cur_scores = self.best_scores.clone();
                 continue;
             }
             if scores.total() >= self.best_scores.total() + self.noise {
@@ -115,6 +206,7 @@ where M: EvalModel<'a>
             }
 
             self.cur_layout = layout;
+            self.cur_scores = scores.clone();
 
             if scores.total() >= self.best_scores.total() {
                 // The layout was accepted but it's not a global improvement.
@@ -142,8 +234,11 @@ where M: EvalModel<'a>
                     self.noise_step *= 0.25;
                 }
 
+                self.best_layout = layout;
                 self.best_scores = scores;
+                self.real_layout = layout;
                 self.real_scores = real_scores.clone();
+                self.reseed();
 
                 return Some(real_scores);
             }
@@ -151,3 +246,72 @@ where M: EvalModel<'a>
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KuehlmakModel, KuehlmakScores};
+
+    static LAYOUT: &str =
+"  q  w  e  r  t  y  u  i  o  p
+  a  s  d  f  g  h  j  k  l ;:
+  z  x  c  v  b  n  m ,< .> /?
+";
+
+    fn text() -> TextStats {
+        TextStats::from_text("The quick brown fox jumps over the lazy dog. \
+            Sphinx of black quartz, judge my vow! How vexingly quick daft \
+            zebras jump; waltz, bad nymph, for quick jigs vex.", false)
+            .unwrap()
+    }
+
+    // Layout and exact total score returned by each iteration of a run
+    fn run<'a>(anneal: impl Iterator<Item = KuehlmakScores<'a>>)
+        -> Vec<(Layout, u64)>
+    {
+        anneal.map(|scores| (scores.layout(), scores.total().to_bits()))
+              .collect()
+    }
+
+    #[test]
+    fn same_seed_same_layouts() {
+        let model = KuehlmakModel::new(None);
+        let text = text();
+        let layout = layout_from_str(LAYOUT).unwrap();
+
+        let a = run(Anneal::new(&model, &text, layout, true, 50, 42));
+        let b = run(Anneal::new(&model, &text, layout, true, 50, 42));
+        let c = run(Anneal::new(&model, &text, layout, true, 50, 43));
+        assert!(!a.is_empty());
+        assert!(a == b);
+        assert!(a != c);
+    }
+
+    // Resuming from a checkpoint that went through serialization continues
+    // exactly like the uninterrupted run
+    #[test]
+    fn checkpoint_resume() {
+        let model = KuehlmakModel::new(None);
+        let text = text();
+        let layout = layout_from_str(LAYOUT).unwrap();
+
+        let mut anneal = Anneal::new(&model, &text, layout, true, 50, 7);
+        let full = run(anneal.by_ref());
+        assert!(full.len() > 10);
+
+        // A checkpoint after the last step resumes with the final layout
+        let json = serde_json::to_string(&anneal.checkpoint()).unwrap();
+        let state: AnnealState = serde_json::from_str(&json).unwrap();
+        let mut resumed = Anneal::resume(&model, &text, &state).unwrap();
+        assert!(resumed.next().is_none());
+        assert!(run([resumed.scores()].into_iter()) == full[full.len() - 1..]);
+
+        let mut anneal = Anneal::new(&model, &text, layout, true, 50, 7);
+        let mut layouts = run(anneal.by_ref().take(10));
+        let json = serde_json::to_string(&anneal.checkpoint()).unwrap();
+        let state: AnnealState = serde_json::from_str(&json).unwrap();
+
+        layouts.extend(run(Anneal::resume(&model, &text, &state).unwrap()));
+        assert!(layouts == full);
+    }
+}
@@ -0,0 +1,164 @@
+use super::{Layout, EvalScores, KuehlmakScores};
+use super::eval::{Hand, FINGER_NAMES, grid_row_col,
+                  LTHUMB, RTHUMB, NUMBER_ROW};
+use super::export::key_name;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+// Position of a key for humans, e.g. "home 4". Columns count from 1 at the
+// left pinky, with 0 and 11 for the outer columns.
+fn key_label(key: usize) -> String {
+    if (LTHUMB..RTHUMB).contains(&key) {
+        format!("left thumb {}", key - LTHUMB + 1)
+    } else if (RTHUMB..NUMBER_ROW).contains(&key) {
+        format!("right thumb {}", key - RTHUMB + 1)
+    } else {
+        let (row, col) = grid_row_col(key);
+        format!("{} {}", ["number", "top", "home", "bottom"][(row + 1) as usize],
+                col + 1)
+    }
+}
+
+// Shortest sequence of key swaps that turns layout a into b. Keys whose
+// symbols are missing from a are left alone.
+pub fn swap_sequence(a: &Layout, b: &Layout) -> Vec<(usize, usize)> {
+    let mut layout = *a;
+    let mut swaps = Vec::new();
+    for k in 0..layout.len() {
+        if layout[k] == b[k] {
+            continue;
+        }
+        // Swapping the right symbols into place one key at a time resolves
+        // each cycle of n keys with n-1 swaps, which is the minimum
+        if let Some(j) = (k+1..layout.len()).find(|&j| layout[j] == b[k]) {
+            layout.swap(k, j);
+            swaps.push((k, j));
+        }
+    }
+    swaps
+}
+
+// Print moved symbols, the swaps that get from layout a to b, score deltas
+// and the n most frequent bigrams and 3-grams that changed category. The
+// n-grams are only available if the layouts were evaluated with extra lists.
+pub fn write_diff<W>(w: &mut W, a: &KuehlmakScores, b: &KuehlmakScores,
+                     n: usize) -> io::Result<()>
+    where W: Write
+{
+    let (layout_a, layout_b) = (a.layout(), b.layout());
+    let finger = |scores: &KuehlmakScores, key| {
+        let (finger, hand) = scores.key_finger(key);
+        format!("{:<12} {}", FINGER_NAMES[finger],
+                match hand {Hand::L => "Left", Hand::R => "Right",
+                            Hand::Any => ""}).trim_end().to_string()
+    };
+
+    writeln!(w, "Moved symbols:")?;
+    let mut moved = 0;
+    for (key, &symbols) in layout_a.iter().enumerate()
+                                   .filter(|(_, s)| s[0] != '\0') {
+        let to = layout_b.iter().position(|s| s[0] == symbols[0]);
+        if to == Some(key) {
+            continue;
+        }
+        moved += 1;
+        write!(w, "{:>4} {:<15} {:<18} -> ", key_name(symbols),
+               key_label(key), finger(a, key))?;
+        match to {
+            Some(to) => writeln!(w, "{:<15} {}", key_label(to), finger(b, to))?,
+            None => writeln!(w, "(missing)")?,
+        }
+    }
+    if moved == 0 {
+        writeln!(w, "  none")?;
+    }
+    writeln!(w)?;
+
+    let swaps = swap_sequence(&layout_a, &layout_b);
+    writeln!(w, "Swaps ({}):", swaps.len())?;
+    let mut layout = layout_a;
+    for (i, &(k, j)) in swaps.iter().enumerate() {
+        writeln!(w, "{:4}. {} <-> {}   ({} <-> {})", i + 1,
+                 key_name(layout[k]), key_name(layout[j]),
+                 key_label(k), key_label(j))?;
+        layout.swap(k, j);
+    }
+    if layout != layout_b {
+        writeln!(w, "  The layouts don't have the same symbols")?;
+    }
+    writeln!(w)?;
+
+    // Scores in the same order as get_scores
+    let mut names: Vec<_> = KuehlmakScores::get_score_names().into_iter()
+                                                          .collect();
+    names.sort_by_key(|&(_, i)| i);
+    let (scores_a, scores_b) = (a.get_scores(), b.get_scores());
+    writeln!(w, "       Score          A         B     Delta")?;
+    for (name, i) in names {
+        writeln!(w, "{:>12}  {:9.2} {:9.2} {:+9.2}", name, scores_a[i],
+                 scores_b[i], scores_b[i] - scores_a[i])?;
+    }
+
+    // Category of each n-gram on both layouts. N-grams that aren't in any
+    // list, such as alternating bigrams, have no category.
+    let categories = |scores: &KuehlmakScores| {
+        let mut map = HashMap::new();
+        for (name, list) in scores.ngram_lists() {
+            let name = name.split(' ').next().unwrap();
+            for (ngram, _, p) in list {
+                map.insert(ngram, (name, p));
+            }
+        }
+        map
+    };
+    let (cat_a, cat_b) = (categories(a), categories(b));
+    if cat_a.is_empty() && cat_b.is_empty() {
+        return Ok(());
+    }
+    let ngrams: HashSet<_> = cat_a.keys().chain(cat_b.keys()).collect();
+    let mut changed: Vec<_> = ngrams.into_iter().filter_map(|ngram| {
+        let (ca, pa) = cat_a.get(ngram).map_or(("-", 0.0), |&c| c);
+        let (cb, pb) = cat_b.get(ngram).map_or(("-", 0.0), |&c| c);
+        (ca != cb).then(|| (ngram, ca, cb, f64::max(pa, pb)))
+    }).collect();
+    changed.sort_by(|x, y| y.3.total_cmp(&x.3).then(x.0.cmp(y.0)));
+
+    for (len, title) in [(2, "Bigrams"), (3, "3-grams")] {
+        writeln!(w)?;
+        writeln!(w, "{} that changed category:", title)?;
+        writeln!(w, " N-gram   Freq  A            B")?;
+        for (ngram, ca, cb, p) in changed.iter()
+                                         .filter(|c| c.0.chars().count() == len)
+                                         .take(n) {
+            writeln!(w, "{:>7} {:6.2}  {:<12} {}",
+                     format!("{:?}", ngram), p, ca, cb)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout_from_str;
+
+    #[test]
+    fn minimal_swaps() {
+        let a = layout_from_str("q w e r t y u i o p\n\
+                                 a s d f g h j k l ;:\n\
+                                 z x c v b n m ,< .> /?").unwrap();
+        // One 3-cycle (e r t) and one 2-cycle (a s)
+        let b = layout_from_str("q w r t e y u i o p\n\
+                                 s a d f g h j k l ;:\n\
+                                 z x c v b n m ,< .> /?").unwrap();
+        let swaps = swap_sequence(&a, &b);
+        assert_eq!(swaps.len(), 3);
+
+        let mut layout = a;
+        for (k, j) in swaps {
+            layout.swap(k, j);
+        }
+        assert!(layout == b);
+        assert!(swap_sequence(&b, &b).is_empty());
+    }
+}
@@ -1,74 +1,168 @@
-use super::{TextStats, Bigram, Trigram};
+use super::{TextStats, Bigram, Trigram, SHIFT};
 use std::fs::OpenOptions;
 use std::io::{self, BufWriter};
 use std::io::Write as IoWrite;
-use std::fmt;
 use std::fmt::Write as FmtWrite;
 use std::path::{Path, PathBuf};
 use std::collections::BTreeMap;
+use std::array;
 use std::ops::Mul;
 use std::ops::RangeInclusive;
 use serde::{Serialize, Deserialize};
 use rand::Rng;
+use rand::seq::SliceRandom;
 use rand::rngs::SmallRng;
 
-// Layout: 2 chars per key (normal/shifted), 10 keys per row, 3 rows
-pub type Layout = [[char; 2]; 30];
+// Layout: 2 chars per key (normal/shifted), 10 keys per row, 3 rows,
+// followed by up to THUMB_KEYS thumb keys per hand. Thumb keys are numbered
+// from the center outwards. Optional number row and outer pinky columns
+// come last. Keys that don't exist are ['\0', '\0'].
+pub const THUMB_KEYS: usize = 3;
+pub const NUM_KEYS: usize = 30 + 2 * THUMB_KEYS + 10 + 2 * 4;
+pub type Layout = [[char; 2]; NUM_KEYS];
+
+pub(crate) const LTHUMB: usize = 30;
+pub(crate) const RTHUMB: usize = LTHUMB + THUMB_KEYS;
+pub(crate) const NUMBER_ROW: usize = RTHUMB + THUMB_KEYS;
+// Outer pinky columns, from the number row to the bottom row
+const LOUTER: usize = NUMBER_ROW + 10;
+const ROUTER: usize = LOUTER + 4;
+// Space is not part of the layout but the model treats it as an extra key
+const SPACE_KEY: usize = NUM_KEYS;
+
+pub(crate) fn is_thumb_key(key: usize) -> bool {
+    (LTHUMB..NUMBER_ROW).contains(&key) || key == SPACE_KEY
+}
 
-pub fn layout_from_str(text: &str) -> Result<Layout, String> {
-    let mut layout: Layout = [[' '; 2]; 30];
-
-    let mut last_line = 0;
-    for (l, line) in text.lines().enumerate().take(3) {
-        last_line = l;
-
-        let mut last_key = 0;
-        for (k, key) in line.split_whitespace().enumerate() {
-            if k >= 10 {
-                return Err(format!(
-                    "Too many keys on row {}. Expected 10 keys per row",
-                    l + 1));
-            }
-            last_key = k;
-
-            let k = l * 10 + k;
-            let mut last_char = 0;
-            for (i, c) in key.chars().enumerate() {
-                if i >= 2 {
-                    return Err(format!(
-                        "Too many characters on row {}, key {}. Expected 1 or 2 characters per key",
-                       l, last_key));
-                }
-                last_char = i;
+// Index of the key in row -1 (number row) to 2 (bottom row) and column -1
+// (left outer column) to 10 (right outer column)
+pub(crate) fn grid_key(row: i8, col: i8) -> usize {
+    match col {
+        -1 => LOUTER + (row + 1) as usize,
+        10 => ROUTER + (row + 1) as usize,
+        _ if row < 0 => NUMBER_ROW + col as usize,
+        _ => row as usize * 10 + col as usize,
+    }
+}
 
-                layout[k][i] = c;
-            }
-            if last_char == 0 {
-                let c = layout[k][0];
-                if !c.is_alphabetic()
-                    || c.to_lowercase().count() != 1
-                    || c.to_uppercase().count() != 1 {
-                    return Err(format!(
-                        "Automatic case conversion failed for '{}' at row {}, key {}",
-                        c, l, last_key));
-                }
-                layout[k][0] = c.to_lowercase().next().unwrap();
-                layout[k][1] = c.to_uppercase().next().unwrap();
-            } else {
-                assert!(last_char == 1);
-            }
+// Inverse of grid_key for all keys that are not thumb keys
+pub(crate) fn grid_row_col(key: usize) -> (i8, i8) {
+    match key {
+        k if k < LTHUMB => ((k / 10) as i8, (k % 10) as i8),
+        k if k < LOUTER => (-1, (k - NUMBER_ROW) as i8),
+        k if k < ROUTER => ((k - LOUTER) as i8 - 1, -1),
+        k => ((k - ROUTER) as i8 - 1, 10),
+    }
+}
+
+// Swap two columns, numbered like in grid_key, in all rows where both keys
+// exist. Returns the number of rows that were swapped.
+pub fn swap_columns(layout: &mut Layout, a: i8, b: i8) -> usize {
+    let mut swapped = 0;
+    for row in -1..=2 {
+        let (ka, kb) = (grid_key(row, a), grid_key(row, b));
+        if ka != kb && layout[ka][0] != '\0' && layout[kb][0] != '\0' {
+            layout.swap(ka, kb);
+            swapped += 1;
+        }
+    }
+    swapped
+}
+
+fn key_from_str(key: &str, row: usize, col: usize) -> Result<[char; 2], String> {
+    let mut symbols = ['\0'; 2];
+    let mut last_char = 0;
+    for (i, c) in key.chars().enumerate() {
+        if i >= 2 {
+            return Err(format!(
+                "Too many characters on row {}, key {}. Expected 1 or 2 characters per key",
+               row, col));
+        }
+        last_char = i;
+
+        symbols[i] = c;
+    }
+    if last_char == 0 {
+        let c = symbols[0];
+        if !c.is_alphabetic()
+            || c.to_lowercase().count() != 1
+            || c.to_uppercase().count() != 1 {
+            return Err(format!(
+                "Automatic case conversion failed for '{}' at row {}, key {}",
+                c, row, col));
         }
-        if last_key+1 < 10 {
+        symbols[0] = c.to_lowercase().next().unwrap();
+        symbols[1] = c.to_uppercase().next().unwrap();
+    } else {
+        assert!(last_char == 1);
+    }
+    Ok(symbols)
+}
+
+// The optional thumb row lists the left and right thumb keys separated by
+// a lone '|'. Anything else following the key rows (e.g. scores in a
+// layout file) is not a thumb row and gets ignored.
+fn is_thumb_row(line: &str) -> bool {
+    line.split_whitespace().any(|key| key == "|") &&
+        line.split_whitespace().all(|key| key.chars().count() <= 2)
+}
+
+pub fn layout_from_str(text: &str) -> Result<Layout, String> {
+    let mut layout: Layout = [['\0'; 2]; NUM_KEYS];
+    let mut lines = text.lines().peekable();
+
+    // The first 3 lines are key rows. A 4th line with at least 10 keys is
+    // another key row, making the first one the number row.
+    let mut rows: Vec<_> = lines.by_ref().take(3).collect();
+    if rows.len() < 3 {
+        return Err(format!("Found only {} rows. Expected 3 or 4 rows",
+                           rows.len()));
+    }
+    if let Some(line) = lines.next_if(|line| !is_thumb_row(line) &&
+            line.split_whitespace().count() >= 10 &&
+            line.split_whitespace().all(|key| key.chars().count() <= 2)) {
+        rows.push(line);
+    }
+    let num_rows = rows.len();
+    let first_row = 3 - num_rows as i8;
+    for (l, line) in rows.into_iter().enumerate() {
+        let keys: Vec<_> = line.split_whitespace().collect();
+        if keys.len() > 12 {
             return Err(format!(
-                "Found only {} keys in row {}. Expected 10 keys per row",
-                last_key+1, last_line));
+                "Too many keys on row {}. Expected 10 to 12 keys per row",
+                l + 1));
+        } else if keys.len() < 10 {
+            return Err(format!(
+                "Found only {} keys in row {}. Expected 10 to 12 keys per row",
+                keys.len(), l + 1));
+        }
+        // 11 keys add the right outer column, 12 keys add both outer columns
+        let first_col = if keys.len() == 12 {-1} else {0};
+        for (k, key) in keys.into_iter().enumerate() {
+            layout[grid_key(first_row + l as i8, first_col + k as i8)] =
+                key_from_str(key, l, k)?;
         }
     }
-    if last_line+1 < 3 {
-        return Err(format!("Found only {} rows. Expected 3 rows",
-                           last_line+1));
+    if let Some(line) = lines.next().filter(|&line| is_thumb_row(line)) {
+        let mut hands = line.splitn(2, '|');
+        let left: Vec<_> = hands.next().unwrap().split_whitespace().collect();
+        let right: Vec<_> = hands.next().unwrap().split_whitespace().collect();
+        if left.len() > THUMB_KEYS || right.len() > THUMB_KEYS {
+            return Err(format!(
+                "Too many thumb keys. Expected up to {} keys per hand",
+                THUMB_KEYS));
+        }
+        // Left thumb keys are written from the outside in
+        let n = left.len();
+        for (k, key) in left.into_iter().enumerate() {
+            layout[LTHUMB + n - 1 - k] = key_from_str(key, num_rows, k)?;
+        }
+        for (k, key) in right.into_iter().enumerate() {
+            layout[RTHUMB + k] = key_from_str(key, num_rows, n + k)?;
+        }
     }
-    let mut symbols: Vec<char> = layout.iter().flatten().copied().collect();
+    let mut symbols: Vec<char> = layout.iter().flatten().copied()
+                                       .filter(|&c| c != '\0').collect();
     symbols.sort_unstable();
     let (dups, _) = symbols.into_iter()
                            .fold((String::new(), '\0'), |(mut dups, prev), c| {
@@ -85,28 +179,54 @@ pub fn layout_from_str(text: &str) -> Result<Layout, String> {
 
 pub fn layout_to_str(layout: &Layout) -> String {
     let mut s = String::new();
-    let mut keys = layout.iter();
-    let mut write10keys = |s: &mut String|
-        keys.by_ref().map(|&[a, b]| match b.to_lowercase().next() {
+    let write_key = |s: &mut String, &[a, b]: &[char; 2]|
+        match b.to_lowercase().next() {
             Some(l) if l == a => write!(s, "  {}", a),
             _                 => write!(s, " {}{}", a, b),
-        }).take(10).fold(Ok(()), fmt::Result::and).unwrap();
-
-    write10keys(&mut s);
-    writeln!(s).unwrap();
-    write10keys(&mut s);
-    writeln!(s).unwrap();
-    write10keys(&mut s);
-    writeln!(s).unwrap();
+        }.unwrap();
+
+    // Rows without a left outer key are indented to line up with the rest
+    let exists = |k: usize| layout[k][0] != '\0';
+    let has_louter = (LOUTER..ROUTER).any(exists);
+    let first_row = if (NUMBER_ROW..LOUTER).any(exists) {-1} else {0};
+    for row in first_row..3 {
+        if exists(grid_key(row, -1)) {
+            write_key(&mut s, &layout[grid_key(row, -1)]);
+        } else if has_louter {
+            s.push_str("   ");
+        }
+        for col in 0..10 {
+            write_key(&mut s, &layout[grid_key(row, col)]);
+        }
+        if exists(grid_key(row, 10)) {
+            write_key(&mut s, &layout[grid_key(row, 10)]);
+        }
+        writeln!(s).unwrap();
+    }
+
+    // Thumb keys, if any, are centered under the key rows
+    let left: Vec<_> = layout[LTHUMB..RTHUMB].iter()
+                                            .take_while(|&&[a, _]| a != '\0')
+                                            .collect();
+    let right: Vec<_> = layout[RTHUMB..NUMBER_ROW].iter()
+                                                  .take_while(|&&[a, _]| a != '\0')
+                                                  .collect();
+    if !left.is_empty() || !right.is_empty() {
+        if has_louter {
+            s.push_str("   ");
+        }
+        s.push_str(&" ".repeat(3 * (5 - left.len())));
+        left.into_iter().rev().for_each(|key| write_key(&mut s, key));
+        s.push_str(" |");
+        right.into_iter().for_each(|key| write_key(&mut s, key));
+        writeln!(s).unwrap();
+    }
     s
 }
 
 pub fn layout_to_filename(layout: &Layout) -> PathBuf {
     let mut s = String::new();
-    for (i, &[a, _]) in layout.iter().enumerate() {
-        if i == 10 || i == 20 {
-            s.push('_');
-        }
+    let push_key = |s: &mut String, a|
         // Some substitutions for characters that don't work well in
         // file names on some OSes.
         s.push(match a {
@@ -124,11 +244,49 @@ pub fn layout_to_filename(layout: &Layout) -> PathBuf {
             '"' => 'W',
             _ => a,
         });
+    let exists = |&k: &usize| layout[k][0] != '\0';
+    let first_row = if (NUMBER_ROW..LOUTER).any(|k| exists(&k)) {-1} else {0};
+    for row in first_row..3 {
+        if row != first_row {
+            s.push('_');
+        }
+        for k in (-1..=10).map(|col| grid_key(row, col)).filter(exists) {
+            push_key(&mut s, layout[k][0]);
+        }
+    }
+    if layout[LTHUMB..NUMBER_ROW].iter().any(|&[a, _]| a != '\0') {
+        s.push('_');
+        for &[a, _] in layout[LTHUMB..RTHUMB].iter().rev()
+                                             .filter(|&&[a, _]| a != '\0') {
+            push_key(&mut s, a);
+        }
+        s.push('_');
+        for &[a, _] in layout[RTHUMB..NUMBER_ROW].iter()
+                                                 .filter(|&&[a, _]| a != '\0') {
+            push_key(&mut s, a);
+        }
     }
     s.push_str(".kbl");
     PathBuf::from(s)
 }
 
+// Mirror a layout from left to right, including thumb keys, number row
+// and outer columns
+fn mirror_layout(layout: &Layout) -> Layout {
+    let mut layout = *layout;
+
+    layout[0..10].reverse();
+    layout[10..20].reverse();
+    layout[20..30].reverse();
+    layout[NUMBER_ROW..LOUTER].reverse();
+    let (left, right) = layout[LTHUMB..NUMBER_ROW].split_at_mut(THUMB_KEYS);
+    left.swap_with_slice(right);
+    let (left, right) = layout[LOUTER..].split_at_mut(4);
+    left.swap_with_slice(right);
+
+    layout
+}
+
 pub mod serde_layout {
     use std::fs;
     use std::fmt;
@@ -184,6 +342,7 @@ pub enum KeyboardType {
     ANSI,
     Angle,
     ISO,
+    Custom,
 }
 
 #[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -193,7 +352,7 @@ pub enum Hand {
     Any,
 }
 
-#[derive(Clone, Copy, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
 enum Finger {
     Lp, // Left pinky
     Lr, // Left ring
@@ -204,25 +363,134 @@ enum Finger {
     Rm, // Right middle
     Rr, // Right ring
     Rp, // Right pinky
+    #[serde(skip)]
     Num
 }
 const LFINGS: RangeInclusive<usize> = (Finger::Lp as usize)..=(Finger::Li as usize);
 const RFINGS: RangeInclusive<usize> = (Finger::Ri as usize)..=(Finger::Rp as usize);
 
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Row {
+    Number,
+    Top,
+    Home,
+    Bottom,
+    Thumb,
+}
+
+// Map a physical key position to its index in the layout. Columns -1 and 10
+// are the outer pinky columns. Thumb keys are numbered left to right across
+// both hands.
+fn key_index(row: Row, col: i8) -> Result<usize, String> {
+    match row {
+        Row::Thumb  if (0..THUMB_KEYS as i8).contains(&col) =>
+            Ok(LTHUMB + THUMB_KEYS - 1 - col as usize),
+        Row::Thumb  if (0..2 * THUMB_KEYS as i8).contains(&col) =>
+            Ok(RTHUMB + col as usize - THUMB_KEYS),
+        Row::Thumb => Err(format!("Column {} is out of range for that row", col)),
+        _ if (-1..=10).contains(&col) => Ok(grid_key(row as i8 - 1, col)),
+        _ => Err(format!("Column {} is out of range for that row", col)),
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyPosDef {
+    row: Row,
+    col: i8,
+}
+
+// Key position in the config file, validated when parsing it
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "KeyPosDef", into = "KeyPosDef")]
+struct KeyPos {
+    key: usize,
+    def: KeyPosDef,
+}
+
+impl TryFrom<KeyPosDef> for KeyPos {
+    type Error = String;
+
+    fn try_from(def: KeyPosDef) -> Result<Self, Self::Error> {
+        Ok(KeyPos {key: key_index(def.row, def.col)?, def})
+    }
+}
+
+impl From<KeyPos> for KeyPosDef {
+    fn from(k: KeyPos) -> Self {
+        k.def
+    }
+}
+
+// Key declaration for KeyboardType::Custom. Coordinates are in units of key
+// widths with y growing downwards. Hand is implied by the finger except for
+// thumbs, where it defaults to the side of the thumb key.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CustomKeyDef {
+    row: Row,
+    col: i8,
+    x: f32,
+    y: f32,
+    finger: Finger,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hand: Option<Hand>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stretch: Option<bool>,
+    cost: u8,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "CustomKeyDef", into = "CustomKeyDef")]
+struct CustomKey {
+    key: usize,
+    def: CustomKeyDef,
+}
+
+impl TryFrom<CustomKeyDef> for CustomKey {
+    type Error = String;
+
+    fn try_from(def: CustomKeyDef) -> Result<Self, Self::Error> {
+        let key = key_index(def.row, def.col)?;
+        let half = if def.row == Row::Thumb {THUMB_KEYS as i8} else {5};
+        let side = if def.col < half {Hand::L} else {Hand::R};
+        let hand = match def.finger {
+            Finger::Lp | Finger::Lr | Finger::Lm | Finger::Li => Hand::L,
+            Finger::Ri | Finger::Rm | Finger::Rr | Finger::Rp => Hand::R,
+            _ => def.hand.unwrap_or(side),
+        };
+        if def.hand.is_some_and(|h| h != hand) || hand == Hand::Any {
+            return Err("Hand does not match finger".to_string());
+        }
+        if (def.finger == Finger::Th) != (def.row == Row::Thumb) {
+            return Err("Thumb keys must use finger Th and vice versa".to_string());
+        }
+        Ok(CustomKey {key, def: CustomKeyDef {hand: Some(hand), ..def}})
+    }
+}
+
+impl From<CustomKey> for CustomKeyDef {
+    fn from(k: CustomKey) -> Self {
+        k.def
+    }
+}
+
 #[derive(Clone, Copy)]
 struct KeyProps {
     hand: Hand,
     finger: Finger,
     is_stretch: bool,
+    x: f32,
+    y: f32,
     d_abs: f32,
-    d_rel: [f32; 31],
+    d_rel: [f32; NUM_KEYS + 1],
     cost: u16,
 }
 
 pub trait EvalScores {
-    fn write<W>(&self, w: &mut W, show_scores: bool) -> io::Result<()>
-        where W: IoWrite;
-    fn write_extra<W>(&self, w: &mut W) -> io::Result<()>
+    fn write<W>(&self, w: &mut W, show_scores: bool, color: bool)
+        -> io::Result<()> where W: IoWrite;
+    fn write_extra<W>(&self, w: &mut W, color: bool) -> io::Result<()>
         where W: IoWrite;
     fn layout(&self) -> Layout;
     fn total(&self) -> f64;
@@ -230,7 +498,9 @@ pub trait EvalScores {
     fn get_scores(&self) -> Vec<f64>;
     fn get_score_names() -> BTreeMap<String, usize>;
 
-    fn write_to_db(&self, dir: &Path, show_scores: bool) -> io::Result<()> {
+    fn write_to_db(&self, dir: &Path, show_scores: bool, seed: Option<u64>)
+        -> io::Result<()>
+    {
         let path: PathBuf =
             [dir, &layout_to_filename(&self.layout())].iter().collect();
         if let Ok(file) = OpenOptions::new()
@@ -241,8 +511,12 @@ pub trait EvalScores {
             let mut w = BufWriter::new(file);
 
             w.write_all(layout_to_str(&self.layout()).as_bytes())?;
-            self.write(&mut w, show_scores)?;
-            self.write_extra(&mut w)?;
+            self.write(&mut w, show_scores, false)?;
+            self.write_extra(&mut w, false)?;
+            if let Some(seed) = seed {
+                // Random seed that reproduces the layout with anneal --seed
+                writeln!(w, "Seed: {}", seed)?;
+            }
             write!(w, "#")?;
 
             w.flush()
@@ -262,8 +536,14 @@ pub trait EvalModel<'a> {
 
     fn eval_layout(&'a self, layout: &Layout, ts: &TextStats,
                    precision: f64, extra: bool) -> Self::Scores;
-    fn key_cost_ranking(&'a self) -> &'a [usize; 30];
+    // Evaluate a layout that differs from the one in prev by only a few
+    // swapped keys, by updating prev rather than starting from scratch.
+    // The result must be identical to eval_layout without extra lists.
+    fn eval_layout_delta(&'a self, prev: &Self::Scores, layout: &Layout,
+                         ts: &TextStats, precision: f64) -> Self::Scores;
+    fn key_cost_ranking(&'a self) -> &'a [usize; NUM_KEYS];
     fn neighbor(&'a self, rng: &mut SmallRng, layout: &Layout) -> Layout;
+    fn shuffle(&'a self, rng: &mut SmallRng, layout: &Layout) -> Layout;
     fn is_symmetrical(&'a self) -> bool;
 }
 
@@ -272,9 +552,18 @@ pub trait EvalModel<'a> {
 pub struct KuehlmakParams {
     board_type: KeyboardType,
     space_thumb: Hand,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shift_key: Option<KeyPos>,
     weights: KuehlmakWeights,
     targets: KuehlmakTargets,
     constraints: ConstraintParams,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    custom_keys: Vec<CustomKey>,
+}
+
+impl KuehlmakParams {
+    pub fn board_type(&self) -> KeyboardType {self.board_type}
+    pub fn has_shift_key(&self) -> bool {self.shift_key.is_some()}
 }
 
 impl Default for KuehlmakParams {
@@ -282,9 +571,11 @@ impl Default for KuehlmakParams {
         KuehlmakParams {
             board_type: KeyboardType::Ortho,
             space_thumb: Hand::Any,
+            shift_key: None,
             weights: KuehlmakWeights::default(),
             targets: KuehlmakTargets::default(),
             constraints: ConstraintParams::default(),
+            custom_keys: Vec::new(),
         }
     }
 }
@@ -296,6 +587,7 @@ pub struct KuehlmakWeights {
     middle_finger: u8,
     ring_finger: u8,
     pinky_finger: u8,
+    thumb_finger: u8,
     effort: f64,
     travel: f64,
     imbalance: f64,
@@ -325,6 +617,7 @@ impl Default for KuehlmakWeights {
             middle_finger: 1,
             ring_finger:   2,
             pinky_finger:  6,
+            thumb_finger:  1,
             effort:        0.2,
             travel:        1.0,
             imbalance:     0.05,
@@ -371,6 +664,27 @@ pub struct KuehlmakTargets {
     contorts: Option<f64>,
 }
 
+// Penalties for symbols that moved away from their pinned key
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct PinWeights {
+    same_key: f64,
+    same_finger: f64,
+    same_hand: f64,
+    other_hand: f64,
+}
+
+impl Default for PinWeights {
+    fn default() -> Self {
+        PinWeights {
+            same_key: 0.0,
+            same_finger: 0.5,
+            same_hand: 0.75,
+            other_hand: 1.0,
+        }
+    }
+}
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 #[serde(default,deny_unknown_fields)]
 pub struct ConstraintParams {
@@ -383,12 +697,19 @@ pub struct ConstraintParams {
     bot_keys: Option<String>,
     homing_keys: Option<String>,
     homing_only_keys: Option<String>,
+    pinned_keys: Option<String>,
+    #[serde(with = "serde_layout")]
+    soft_pins: Option<Layout>,
+    soft_pin_keys: Option<String>,
+    soft_pin_threshold: f64,
     top_weight: f64,
     mid_weight: f64,
     bot_weight: f64,
     homing_weight: f64,
     zxcv: f64,
     nonalpha: f64,
+    // Tables must come last for serializing to TOML
+    soft_pin_weights: PinWeights,
 }
 
 #[derive(Clone)]
@@ -397,11 +718,12 @@ pub struct KuehlmakScores<'a> {
     layout: Layout,
     token_keymap: Vec<u8>,
     strokes: u64,
-    heatmap: [u64; 31],
+    heatmap: [u64; NUM_KEYS + 1],
     bigram_counts: [[u64; 2]; BIGRAM_NUM_TYPES],
     trigram_counts: [[u64; 2]; TRIGRAM_NUM_TYPES],
     bigram_lists: [Option<Vec<(Bigram, u64)>>; BIGRAM_NUM_TYPES],
     trigram_lists: [Option<Vec<(Trigram, u64)>>; TRIGRAM_NUM_TYPES],
+    raw: NGramCounts,
     finger_travel: [f64; Finger::Num as usize],
     urolls: [f64; 2],
     wlsbs: [f64; 2],
@@ -417,19 +739,94 @@ pub struct KuehlmakScores<'a> {
     constraints: f64,
 }
 
+// Unscaled n-gram statistics within the precision cut-off. They are kept
+// with the scores, so that evaluating a key swap only needs to update the
+// n-grams on the swapped keys. Travel corrections are accumulated in fixed
+// point, which makes the sums independent of the order of updates.
+#[derive(Clone, Default)]
+struct NGramCounts {
+    precision: f64,
+    bigram_cut: usize,
+    bigram_total: u64,
+    trigram_cut: usize,
+    trigram_total: u64,
+    bigrams: [[u64; 2]; BIGRAM_NUM_TYPES],
+    trigrams: [[u64; 2]; TRIGRAM_NUM_TYPES],
+    same_hand: [u64; 2],
+    bigram_travel: [i128; Finger::Num as usize],
+    trigram_travel: [i128; Finger::Num as usize],
+}
+
+const TRAVEL_SCALE: f64 = 65536.0;
+
+// Bigram or 3-gram on a layout: type, hand, finger and travel correction
+type NGramClass = (usize, usize, usize, i64);
+
 #[derive(Clone)]
 pub struct KuehlmakModel {
     params: KuehlmakParams,
-    key_props: [KeyProps; 31],
-    bigram_types: [[u8; 31]; 31],
-    trigram_types: [[[u8; 31]; 31]; 31],
-    key_cost_ranking: [usize; 30],
+    key_props: [KeyProps; NUM_KEYS + 1],
+    bigram_types: [[u8; NUM_KEYS + 1]; NUM_KEYS + 1],
+    trigram_types: [[[u8; NUM_KEYS + 1]; NUM_KEYS + 1]; NUM_KEYS + 1],
+    key_cost_ranking: [usize; NUM_KEYS],
     finger_keys: [Vec<u8>; Finger::Num as usize],
 }
 
+// ANSI terminal colors. Heat map buckets go from blue for the coldest
+// keys through cyan, grey, magenta and red to yellow for the hottest keys,
+// with a readable font color for each background.
+const ANSI_RESET: &str = "\x1b[0m";
+const ANSI_HEAT: [&str; 6] = ["\x1b[97;44m", "\x1b[30;46m", "\x1b[30;47m",
+                              "\x1b[97;45m", "\x1b[97;41m", "\x1b[30;43m"];
+const ANSI_GOOD: &str = "\x1b[32m";
+const ANSI_BAD: &str = "\x1b[31m";
+
+// Writes text in the given color, if any
+fn write_color<W: IoWrite>(w: &mut W, color: Option<&str>,
+                           args: std::fmt::Arguments) -> io::Result<()> {
+    match color {
+        Some(c) => write!(w, "{}{}{}", c, args, ANSI_RESET),
+        None => w.write_fmt(args),
+    }
+}
+
+// Heat map bucket of each key with the buckets representing about the same
+// number of keystrokes. Keys that are never used get no color.
+pub(crate) fn heat_buckets(heat: &[f64]) -> Vec<Option<usize>> {
+    let mut keys: Vec<_> = (0..heat.len()).filter(|&k| heat[k] > 0.0).collect();
+    keys.sort_by(|&a, &b| heat[a].total_cmp(&heat[b]));
+    let total: f64 = keys.iter().map(|&k| heat[k]).sum();
+    let mut buckets = vec![None; heat.len()];
+    let mut sum = 0.0;
+    for k in keys {
+        let b = (sum + heat[k] / 2.0) / total * ANSI_HEAT.len() as f64;
+        buckets[k] = Some((b as usize).min(ANSI_HEAT.len() - 1));
+        sum += heat[k];
+    }
+    buckets
+}
+
+// Good or bad color for a score relative to its target. Scores within 10%
+// of the target are neutral.
+fn target_color(score: f64, weight: f64, target: Option<f64>, factor: f64)
+    -> Option<&'static str>
+{
+    let target = target.filter(|_| factor > 0.0 && weight != 0.0)?;
+    let (better, worse) = if weight > 0.0 {(score < target * 0.9,
+                                            score > target * 1.1)}
+                          else {(score > target * 1.1, score < target * 0.9)};
+    if better {
+        Some(ANSI_GOOD)
+    } else if worse {
+        Some(ANSI_BAD)
+    } else {
+        None
+    }
+}
+
 impl<'a> EvalScores for KuehlmakScores<'a> {
-    fn write<W>(&self, w: &mut W, show_scores: bool) -> io::Result<()>
-    where W: IoWrite {
+    fn write<W>(&self, w: &mut W, show_scores: bool, color: bool)
+        -> io::Result<()> where W: IoWrite {
         let norm = 1000.0 / self.strokes as f64;
         let mut fh = [0u64; Finger::Num as usize];
         let (mut raw_effort, mut raw_left, mut raw_right) = (0u64, 0u64, 0u64);
@@ -462,7 +859,8 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
         let raw_travel = self.finger_travel.iter().sum::<f64>() * norm;
 
         let key_space = match self.model.params.board_type {
-                KeyboardType::Ortho | KeyboardType::ColStag =>
+                KeyboardType::Ortho | KeyboardType::ColStag |
+                KeyboardType::Custom =>
                     [["  ", " ||| ", "|", "|", "  |||", "  "]; 3],
                 KeyboardType::Hex | KeyboardType::HexStag  =>
                     [["", "  ///", "\\   /", " \\ / ", " \\\\\\ ", ""],
@@ -482,36 +880,82 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
                      ["", " /// ", " [*]\\", "  -  ", "\\ \\\\\\", ""]],
             };
 
-        let mut layout_iter = self.layout().into_iter();
-        let mut write_5keys = |w: &mut W|
-            layout_iter.by_ref().take(5)
-                       .map(|[a, b]| match b.to_lowercase().next() {
-                           Some(l) if l == a => write!(w, " [{}]", b),
-                           _                 => write!(w, "[{}{}]", a, b),
-                       }).fold(Ok(()), io::Result::and);
-        let mut write_key_row = |w: &mut W, [prefix,_,sep,_,_,suffix]: [&str; 6]| {
+        // Outer columns are only shown if the layout has any. Rows without
+        // a left outer key are indented to line up with the rest.
+        let layout = self.layout();
+        let exists = |k: usize| layout[k][0] != '\0';
+        let has_louter = (LOUTER..ROUTER).any(exists);
+        let heat = |k: usize| match show_scores {
+            false => self.heatmap[k],
+            true  => self.heatmap[k] * self.model.key_props[k].cost as u64,
+        } as f64 * norm;
+        let buckets = heat_buckets(&(0..SPACE_KEY).map(heat).collect::<Vec<_>>());
+        let heat_color = |k: usize| buckets.get(k).copied().flatten()
+                                           .filter(|_| color)
+                                           .map(|b| ANSI_HEAT[b]);
+        let write_key = |w: &mut W, k: usize| {
+            let [a, b] = layout[k];
+            match b.to_lowercase().next() {
+                Some(l) if l == a => {
+                    write!(w, " ")?;
+                    write_color(w, heat_color(k), format_args!("[{}]", b))
+                },
+                _ => write_color(w, heat_color(k), format_args!("[{}{}]", a, b)),
+            }
+        };
+        let write_key_row = |w: &mut W, row, [prefix,_,sep,_,_,suffix]: [&str; 6]| {
             w.write_all(prefix.as_bytes())?;
-            write_5keys(w)?;
+            if exists(grid_key(row, -1)) {
+                write_key(w, grid_key(row, -1))?;
+            } else if has_louter {
+                write!(w, "    ")?;
+            }
+            for col in 0..5 {
+                write_key(w, grid_key(row, col))?;
+            }
             w.write_all(sep.as_bytes())?;
-            write_5keys(w)?;
+            for col in 5..10 {
+                write_key(w, grid_key(row, col))?;
+            }
+            if exists(grid_key(row, 10)) {
+                write_key(w, grid_key(row, 10))?;
+            }
             writeln!(w, "{}", suffix)
         };
 
-        let mut heat_iter = self.heatmap.iter().zip(self.model.key_props.iter())
-                .map(|(&h, &props)| if show_scores {h * props.cost as u64} else {h});
-        let mut write_5heats = |w: &mut W, sep: &str|
-            heat_iter.by_ref().take(5).zip(sep.chars())
-                     .map(|(h, s)| write!(w, "{}{:^3.0}", s, h as f64 * norm))
-                     .fold(Ok(()), io::Result::and);
-        let mut write_heat_row = |w: &mut W, [prefix,lsep,_,sep,rsep,suffix]: [&str; 6]| {
+        let write_heat = |w: &mut W, k: usize|
+            write_color(w, heat_color(k), format_args!("{:^3.0}", heat(k)));
+        let write_heat_row = |w: &mut W, row, [prefix,lsep,_,sep,rsep,suffix]: [&str; 6]| {
             w.write_all(prefix.as_bytes())?;
-            write_5heats(w, lsep)?;
+            if exists(grid_key(row, -1)) {
+                write!(w, " ")?;
+                write_heat(w, grid_key(row, -1))?;
+            } else if has_louter {
+                write!(w, "    ")?;
+            }
+            for (col, s) in (0..5).zip(lsep.chars()) {
+                write!(w, "{}", s)?;
+                write_heat(w, grid_key(row, col))?;
+            }
             w.write_all(sep.as_bytes())?;
-            write_5heats(w, rsep)?;
+            for (col, s) in (5..10).zip(rsep.chars()) {
+                write!(w, "{}", s)?;
+                write_heat(w, grid_key(row, col))?;
+            }
+            if exists(grid_key(row, 10)) {
+                write!(w, " ")?;
+                write_heat(w, grid_key(row, 10))?;
+            }
             writeln!(w, "{}", suffix)
         };
 
-        let write_ngram_u = |w: &mut W, g: [u64; 2]| {
+        // N-gram scores are colored relative to their targets
+        let targets = &self.model.params.targets;
+        let score_color = |score: f64, weight: f64, target: Option<f64>|
+            target_color(score, weight, target, targets.factor)
+                .filter(|_| color);
+        let weights = &self.model.params.weights;
+        let write_ngram_u = |w: &mut W, g: [u64; 2], weight, target| {
             let ind = if g[0]     >= g[1] * 3 {'«'}  // worse than 75:25
                  else if g[0] * 2 >= g[1] * 3 {'‹'}  // 75:25 - 60:40
                  else if g[0] * 3 >  g[1] * 2 {' '}  // 60:40 - 40:60
@@ -521,9 +965,11 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
                 false => (g[0] + g[1]) as f64,
                 true  => Self::get_lr_score_u(g),
             } * norm;
-            write!(w, "{:5.1}{}", val, ind)
+            let c = score_color(Self::get_lr_score_u(g) * norm, weight, target);
+            write_color(w, c, format_args!("{:5.1}", val))?;
+            write!(w, "{}", ind)
         };
-        let write_ngram_f = |w: &mut W, g: [f64; 2]| {
+        let write_ngram_f = |w: &mut W, g: [f64; 2], weight, target| {
             let ind = if g[0]       >= g[1] * 3.0 {'«'}
                  else if g[0] * 2.0 >= g[1] * 3.0 {'‹'}
                  else if g[0] * 3.0 >  g[1] * 2.0 {' '}
@@ -533,47 +979,67 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
                 false => g[0] + g[1],
                 true  => Self::get_lr_score_f(g),
             } * norm;
-            write!(w, "{:5.1}{}", val, ind)
+            let c = score_color(Self::get_lr_score_f(g) * norm, weight, target);
+            write_color(w, c, format_args!("{:5.1}", val))?;
+            write!(w, "{}", ind)
         };
 
+        // The number row is only shown if the layout has one
+        if (-1..=10).any(|col| exists(grid_key(-1, col))) {
+            write!(w, "{:34}|", "")?;
+            write_key_row(w, -1, key_space[0])?;
+            write!(w, "{:34}|", "")?;
+            write_heat_row(w, -1, key_space[0])?;
+        }
+
         write!(w, "Score+Con{:7.1}{:+8.1} ={:7.1} |",
                self.total * 1000.0, self.constraints * 1000.0,
                (self.total + self.constraints) * 1000.0)?;
-        write_key_row(w, key_space[0])?;
+        write_key_row(w, 0, key_space[0])?;
 
         write!(w, "    DRoll URoll  WLSB Scissor SFB |")?;
-        write_heat_row(w, key_space[0])?;
+        write_heat_row(w, 0, key_space[0])?;
 
         write!(w, " AB ")?;
-        write_ngram_u(w, self.bigram_counts[BIGRAM_DROLL])?;
-        write_ngram_f(w, self.urolls)?;
-        write_ngram_f(w, self.wlsbs)?;
-        write_ngram_u(w, self.bigram_counts[BIGRAM_SCISSOR])?;
-        write_ngram_u(w, self.bigram_counts[BIGRAM_SFB])?;
+        write_ngram_u(w, self.bigram_counts[BIGRAM_DROLL],
+                      weights.drolls, targets.drolls)?;
+        write_ngram_f(w, self.urolls, weights.urolls, targets.urolls)?;
+        write_ngram_f(w, self.wlsbs, weights.wlsbs, targets.wlsbs)?;
+        write_ngram_u(w, self.bigram_counts[BIGRAM_SCISSOR],
+                      weights.scissors, targets.scissors)?;
+        write_ngram_u(w, self.bigram_counts[BIGRAM_SFB],
+                      weights.sfbs, targets.sfbs)?;
         write!(w, "|")?;
-        write_key_row(w, key_space[1])?;
+        write_key_row(w, 1, key_space[1])?;
 
         write!(w, "A_B ")?;
-        write_ngram_u(w, self.trigram_counts[TRIGRAM_D_DROLL])?;
-        write_ngram_f(w, self.d_urolls)?;
-        write_ngram_f(w, self.d_wlsbs)?;
-        write_ngram_u(w, self.trigram_counts[TRIGRAM_D_SCISSOR])?;
-        write_ngram_u(w, self.trigram_counts[TRIGRAM_D_SFB])?;
+        write_ngram_u(w, self.trigram_counts[TRIGRAM_D_DROLL],
+                      weights.d_drolls, targets.d_drolls)?;
+        write_ngram_f(w, self.d_urolls, weights.d_urolls, targets.d_urolls)?;
+        write_ngram_f(w, self.d_wlsbs, weights.d_wlsbs, targets.d_wlsbs)?;
+        write_ngram_u(w, self.trigram_counts[TRIGRAM_D_SCISSOR],
+                      weights.d_scissors, targets.d_scissors)?;
+        write_ngram_u(w, self.trigram_counts[TRIGRAM_D_SFB],
+                      weights.d_sfbs, targets.d_sfbs)?;
         write!(w, "|")?;
-        write_heat_row(w, key_space[1])?;
+        write_heat_row(w, 1, key_space[1])?;
 
         write!(w, "    RRoll Redir Contort  Runs L:R |")?;
-        write_key_row(w, key_space[2])?;
+        write_key_row(w, 2, key_space[2])?;
 
         write!(w, "ABC ")?;
-        write_ngram_u(w, self.trigram_counts[TRIGRAM_RROLL])?;
-        write_ngram_u(w, self.redirects)?;
-        write_ngram_u(w, self.contorts)?;
+        write_ngram_u(w, self.trigram_counts[TRIGRAM_RROLL],
+                      weights.rrolls, targets.rrolls)?;
+        write_ngram_u(w, self.redirects, weights.redirects, targets.redirects)?;
+        write_ngram_u(w, self.contorts, weights.contorts, targets.contorts)?;
         write!(w, "  {:4.2}:{:4.2} |", self.hand_runs[0], self.hand_runs[1])?;
-        write_heat_row(w, key_space[2])?;
+        write_heat_row(w, 2, key_space[2])?;
 
-        write!(w, "Travel {:6.1} ({:6.1})            |",
-               self.travel * 1000.0, raw_travel)?;
+        write!(w, "Travel ")?;
+        write_color(w, score_color(self.travel * 1000.0, weights.travel,
+                                   targets.travel),
+                    format_args!("{:6.1}", self.travel * 1000.0))?;
+        write!(w, " ({:6.1})            |", raw_travel)?;
         write!(w, "{:3.0}+{:3.0}+{:3.0}+{:3.0}={:<3.0}",
                ft_iter.next().unwrap(), ft_iter.next().unwrap(),
                ft_iter.next().unwrap(), ft_iter.next().unwrap(),
@@ -588,16 +1054,22 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
                  ft_iter.next().unwrap(), ft_iter.next().unwrap(),
                  ft_iter.next().unwrap(), ft_iter.next().unwrap())?;
 
-        write!(w, "Effort{:7.1} ({:6.1}) {:+7.2}% {} |",
-               self.effort * 1000.0, raw_effort, self.imbalance * 100.0,
-               if raw_left > raw_right {'<'} else {'>'})?;
+        write!(w, "Effort")?;
+        write_color(w, score_color(self.effort * 1000.0, weights.effort,
+                                   targets.effort),
+                    format_args!("{:7.1}", self.effort * 1000.0))?;
+        write!(w, " ({:6.1}) ", raw_effort)?;
+        write_color(w, score_color(self.imbalance * 100.0, weights.imbalance,
+                                   targets.imbalance),
+                    format_args!("{:+7.2}%", self.imbalance * 100.0))?;
+        write!(w, " {} |", if raw_left > raw_right {'<'} else {'>'})?;
         write!(w, "{:3.0}+{:3.0}+{:3.0}+{:3.0}={:<4.0}",
                fh_iter.next().unwrap(), fh_iter.next().unwrap(),
                fh_iter.next().unwrap(), fh_iter.next().unwrap(),
                hh_iter.next().unwrap())?;
         write!(w, "{}{:^3.0}{}",
                 if let Hand::L = self.model.params.space_thumb {'+'} else {' '},
-                self.heatmap[30] as f64 * norm,
+                self.heatmap[SPACE_KEY] as f64 * norm,
                 if let Hand::R = self.model.params.space_thumb {'+'} else {' '}
                 )?;
         writeln!(w, "{:4.0}={:3.0}+{:3.0}+{:3.0}+{:3.0}",
@@ -605,16 +1077,80 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
                  fh_iter.next().unwrap(), fh_iter.next().unwrap(),
                  fh_iter.next().unwrap(), fh_iter.next().unwrap())?;
 
+        // Thumb keys are only shown if the layout has any. They are lined
+        // up around Space in the same columns as the per-hand totals above.
+        let left: Vec<_> = (LTHUMB..RTHUMB).take_while(|&k| layout[k][0] != '\0')
+                                           .collect();
+        let right: Vec<_> = (RTHUMB..NUMBER_ROW).take_while(|&k| layout[k][0] != '\0')
+                                                .collect();
+        if !left.is_empty() || !right.is_empty() {
+            let indent = 18 - 4 * left.len();
+
+            write!(w, "Thumbs{:28}|{:indent$}", "", "")?;
+            for &k in left.iter().rev() {
+                write_key(w, k)?;
+            }
+            write!(w, " [___]")?;
+            for &k in right.iter() {
+                write_key(w, k)?;
+            }
+            writeln!(w)?;
+
+            write!(w, "{:34}|{:indent$}", "", "")?;
+            for &k in left.iter().rev() {
+                write!(w, " ")?;
+                write_heat(w, k)?;
+            }
+            write!(w, " {:^5.0}", heat(SPACE_KEY))?;
+            for &k in right.iter() {
+                write!(w, " ")?;
+                write_heat(w, k)?;
+            }
+            writeln!(w)?;
+        }
+
         Ok(())
     }
 
-    fn write_extra<W>(&self, w: &mut W) -> io::Result<()>
+    fn write_extra<W>(&self, w: &mut W, color: bool) -> io::Result<()>
     where W: IoWrite {
         let norm = 1000.0 / self.strokes as f64;
+
+        // N-gram types with a score are colored relative to their targets
+        let weights = &self.model.params.weights;
+        let targets = &self.model.params.targets;
+        let write_name = |w: &mut W, name, score: Option<(f64, f64, Option<f64>)>| {
+            let c = score.and_then(|(score, weight, target)|
+                target_color(score * norm, weight, target, targets.factor)
+            ).filter(|_| color);
+            write_color(w, c, format_args!("{}", name))?;
+            writeln!(w, ":")
+        };
+        let u = Self::get_lr_score_u;
+        let f = Self::get_lr_score_f;
+        let bigram_scores = [None,
+            Some((u(self.bigram_counts[BIGRAM_DROLL]), weights.drolls, targets.drolls)),
+            Some((f(self.urolls), weights.urolls, targets.urolls)),
+            None, None, None, None,
+            Some((u(self.bigram_counts[BIGRAM_SCISSOR]), weights.scissors, targets.scissors)),
+            Some((u(self.bigram_counts[BIGRAM_SFB]), weights.sfbs, targets.sfbs))];
+        let trigram_scores = [None, None, None,
+            Some((u(self.trigram_counts[TRIGRAM_D_SFB]), weights.d_sfbs, targets.d_sfbs)),
+            None,
+            Some((u(self.trigram_counts[TRIGRAM_D_DROLL]), weights.d_drolls, targets.d_drolls)),
+            Some((f(self.d_urolls), weights.d_urolls, targets.d_urolls)),
+            None, None, None,
+            Some((u(self.trigram_counts[TRIGRAM_D_SCISSOR]), weights.d_scissors, targets.d_scissors)),
+            Some((u(self.trigram_counts[TRIGRAM_RROLL]), weights.rrolls, targets.rrolls)),
+            Some((u(self.redirects), weights.redirects, targets.redirects)),
+            Some((u(self.contorts), weights.contorts, targets.contorts))];
+
+        let shift_key = self.model.params.shift_key.map(|p| p.key);
         let is_side = |side, c| if c == ' '
-            {self.model.params.space_thumb == side} else
+            {self.model.params.space_thumb == side} else if c == SHIFT
+            {shift_key.map(|k| self.model.key_props[k].hand) == Some(side)} else
             {self.layout().iter().position(|&[l, u]| l == c || u == c)
-                                 .unwrap() % 10 / 5 == side as usize};
+                          .map(|k| self.model.key_props[k].hand) == Some(side)};
         let write_2gram_freqs = |w: &mut W, vec: &Vec<(Bigram, u64)>, side|
                 -> io::Result<f64> {
             let mut sum = 0.0;
@@ -629,16 +1165,13 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
             Ok(sum)
         };
 
-        let bigram_names = ["", "DRolls", "URolls", "SameKey",
-            "LSB3s (count as 1/3 WLSBs, 2/3 URolls)",
-            "LSB2s (count as 1/2 WLSBs, 1/2 URolls)",
-            "LSB1s", "Scissors", "SFBs"];
-        for (vec, name) in self.bigram_lists.iter()
-                               .zip(bigram_names.into_iter())
-                               .filter_map(|(vec, name)|
-                                    vec.as_ref().map(|vec| (vec, name))) {
+        for ((vec, name), score) in self.bigram_lists.iter()
+                               .zip(BIGRAM_NAMES.into_iter())
+                               .zip(bigram_scores)
+                               .filter_map(|((vec, name), score)|
+                                    vec.as_ref().map(|vec| ((vec, name), score))) {
             writeln!(w)?;
-            writeln!(w, "{}:", name)?;
+            write_name(w, name, score)?;
             write!(w, " Left hand:")?;
             let left_sum = write_2gram_freqs(w, vec, Hand::L)?;
             writeln!(w)?;
@@ -664,18 +1197,13 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
             Ok(sum)
         };
 
-        let trigram_names = ["",
-            "dSameKey", "shdSameKey (count as Redirects)",
-            "dSFBs", "shdSFBs (count as Contorts)", "dDRolls", "dURolls",
-            "dLSB3s (count as 1/3 dWLSBs, 2/3 dUROLLS)",
-            "dLSB2s (count as 1/2 dWLSBs, 1/2 dURolls)",
-            "dLSB1s", "dScissors", "RRolls", "Redirects", "Contortions"];
-        for (vec, name) in self.trigram_lists.iter()
-                               .zip(trigram_names.into_iter())
-                               .filter_map(|(vec, name)|
-                                    vec.as_ref().map(|vec| (vec, name))) {
+        for ((vec, name), score) in self.trigram_lists.iter()
+                               .zip(TRIGRAM_NAMES.into_iter())
+                               .zip(trigram_scores)
+                               .filter_map(|((vec, name), score)|
+                                    vec.as_ref().map(|vec| ((vec, name), score))) {
             writeln!(w)?;
-            writeln!(w, "{}:", name)?;
+            write_name(w, name, score)?;
             write!(w, " Left hand:")?;
             let left_sum = write_3gram_freqs(w, vec, Hand::L)?;
             writeln!(w)?;
@@ -693,14 +1221,11 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
         if self.model.is_symmetrical() {
             if let Some(i) = self.layout.iter()
                                  .position(|&[l, u]| l == '.' || u == '.') {
-                if i % 10 < 5 {
-                    let mut layout = self.layout;
-
-                    layout[0..10].reverse();
-                    layout[10..20].reverse();
-                    layout[20..30].reverse();
-
-                    return layout;
+                let mirror = mirror_layout(&self.layout);
+                let same_keys = mirror.iter().zip(self.layout.iter())
+                                      .all(|(m, l)| (m[0] == '\0') == (l[0] == '\0'));
+                if self.model.key_props[i].hand == Hand::L && same_keys {
+                    return mirror;
                 }
             }
         }
@@ -755,6 +1280,132 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
     }
 }
 
+// Usage of a key for reports in other formats, such as HTML. The position
+// is in key widths relative to the left end of the home row.
+#[derive(Clone, Copy)]
+pub(crate) struct KeyUsage {
+    pub key: usize,
+    pub symbols: [char; 2],
+    pub x: f32,
+    pub y: f32,
+    pub usage: f64,
+    pub cost: u16,
+}
+
+// N-grams with the hand of their first key and their frequency
+pub(crate) type NgramList = Vec<(String, Hand, f64)>;
+
+// Pairs of keys of bigrams with their frequency
+pub(crate) type KeyPairs = Vec<(usize, usize, f64)>;
+
+pub(crate) const FINGER_NAMES: [&str; Finger::Num as usize] = [
+    "Left pinky", "Left ring", "Left middle", "Left index", "Thumbs",
+    "Right index", "Right middle", "Right ring", "Right pinky",
+];
+
+// Data for reports in other formats, normalized per 1000 keystrokes
+impl<'a> KuehlmakScores<'a> {
+    // Keys that exist in the layout, followed by Space
+    pub(crate) fn key_usage(&self) -> Vec<KeyUsage> {
+        let norm = 1000.0 / self.strokes as f64;
+        let layout = self.layout();
+        (0..=SPACE_KEY).filter(|&k| k == SPACE_KEY || layout[k][0] != '\0')
+                       .map(|key| {
+            let props = &self.model.key_props[key];
+            KeyUsage {
+                key,
+                symbols: if key == SPACE_KEY {[' ', '\0']} else {layout[key]},
+                x: props.x,
+                y: props.y,
+                usage: self.heatmap[key] as f64 * norm,
+                cost: props.cost,
+            }
+        }).collect()
+    }
+
+    // Usage, cost-weighted usage and travel of each finger
+    pub(crate) fn finger_usage(&self) -> [(f64, f64, f64); Finger::Num as usize] {
+        let norm = 1000.0 / self.strokes as f64;
+        let mut fingers = [(0.0, 0.0, 0.0); Finger::Num as usize];
+        for (&count, props) in self.heatmap.iter().zip(self.model.key_props.iter()) {
+            let f = &mut fingers[props.finger as usize];
+            f.0 += count as f64 * norm;
+            f.1 += (count * props.cost as u64) as f64 * norm;
+        }
+        for (f, &travel) in fingers.iter_mut().zip(self.finger_travel.iter()) {
+            f.2 = travel * norm;
+        }
+        fingers
+    }
+
+    // N-gram lists by category. Only available if the layout was evaluated with extra lists.
+    pub(crate) fn ngram_lists(&self) -> Vec<(&'static str, NgramList)> {
+        let norm = 1000.0 / self.strokes as f64;
+        let shift_key = self.model.params.shift_key.map(|p| p.key);
+        let layout = self.layout();
+        let hand = |c| if c == ' ' {self.model.params.space_thumb}
+            else if c == SHIFT {
+                shift_key.map_or(Hand::Any, |k| self.model.key_props[k].hand)
+            } else {
+                layout.iter().position(|&[l, u]| l == c || u == c)
+                      .map_or(Hand::Any, |k| self.model.key_props[k].hand)
+            };
+        let bigrams = self.bigram_lists.iter().zip(BIGRAM_NAMES)
+            .filter_map(|(vec, name)| vec.as_ref().map(|vec| (name,
+                vec.iter().map(|&(ngram, num)|
+                    (ngram.iter().collect(), hand(ngram[0]), num as f64 * norm)
+                ).collect())));
+        let trigrams = self.trigram_lists.iter().zip(TRIGRAM_NAMES)
+            .filter_map(|(vec, name)| vec.as_ref().map(|vec| (name,
+                vec.iter().map(|&(ngram, num)|
+                    (ngram.iter().collect(), hand(ngram[0]), num as f64 * norm)
+                ).collect())));
+        bigrams.chain(trigrams).collect()
+    }
+
+    // Finger (index into FINGER_NAMES) and hand of a key
+    pub(crate) fn key_finger(&self, key: usize) -> (usize, Hand) {
+        let props = &self.model.key_props[key];
+        (props.finger as usize, props.hand)
+    }
+
+    // Estimated average length of same-hand key sequences per hand
+    pub(crate) fn hand_runs(&self) -> [f64; 2] {
+        self.hand_runs
+    }
+
+    // Most frequent SFBs and scissors as pairs of keys. Only available if
+    // the layout was evaluated with extra lists.
+    pub(crate) fn sfb_keys(&self, n: usize) -> KeyPairs {
+        self.bigram_keys(BIGRAM_SFB, n)
+    }
+    pub(crate) fn scissor_keys(&self, n: usize) -> KeyPairs {
+        self.bigram_keys(BIGRAM_SCISSOR, n)
+    }
+    fn bigram_keys(&self, bigram_type: usize, n: usize) -> KeyPairs {
+        let norm = 1000.0 / self.strokes as f64;
+        let shift_key = self.model.params.shift_key.map(|p| p.key);
+        let layout = self.layout();
+        let key = |c| if c == ' ' {Some(SPACE_KEY)}
+            else if c == SHIFT {shift_key}
+            else {layout.iter().position(|&[l, u]| l == c || u == c)};
+        // Different bigrams, e.g. with upper and lower case letters, can
+        // map to the same pair of keys
+        let mut list: KeyPairs = Vec::new();
+        for &(ngram, num) in self.bigram_lists[bigram_type].iter().flatten() {
+            if let (Some(a), Some(b)) = (key(ngram[0]), key(ngram[1])) {
+                match list.iter_mut().find(|&&mut (x, y, _)| (x, y) == (a, b)) {
+                    Some(entry) => entry.2 += num as f64 * norm,
+                    None => list.push((a, b, num as f64 * norm)),
+                }
+            }
+        }
+        list.sort_by(|a, b| b.2.total_cmp(&a.2));
+        list.truncate(n);
+        list
+    }
+}
+
 impl<'a> KuehlmakScores<'a> {
     fn get_lr_score_f(c: [f64; 2]) -> f64 {
         (c[0].powi(2) + c[1].powi(2)).mul(2.0).sqrt()
@@ -791,11 +1442,12 @@ impl<'a> EvalModel<'a> for KuehlmakModel {
             constraints: self.eval_constraints(layout),
             token_keymap: Vec::new(),
             strokes: 0,
-            heatmap: [0; 31],
+            heatmap: [0; NUM_KEYS + 1],
             bigram_counts: [[0; 2]; BIGRAM_NUM_TYPES],
             trigram_counts: [[0; 2]; TRIGRAM_NUM_TYPES],
             bigram_lists: [None, bl(), bl(), bl(), bl(), bl(), bl(), bl(), bl()],
             trigram_lists: [None, tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl()],
+            raw: NGramCounts::default(),
             finger_travel: [0.0; Finger::Num as usize],
             urolls: [0.0; 2],
             wlsbs: [0.0; 2],
@@ -810,21 +1462,172 @@ impl<'a> EvalModel<'a> for KuehlmakModel {
             total: 0.0,
         };
 
+        self.map_symbols(ts, &mut scores);
+        self.count_ngrams(ts, &mut scores, 0.9 + precision * 0.1);
+        self.calc_scores(ts, &mut scores);
+
+        scores
+    }
+    fn eval_layout_delta(&'a self, prev: &Self::Scores, layout: &Layout,
+                         ts: &TextStats, precision: f64) -> Self::Scores {
+        // Symbol tokens on the keys that changed
+        let mut tokens = Vec::new();
+        for (new, old) in layout.iter().zip(prev.layout.iter())
+                                .filter(|(new, old)| new != old) {
+            for &(_, t) in new.iter().chain(old.iter())
+                              .filter_map(|&s| ts.get_symbol([s])) {
+                if !tokens.contains(&t) {
+                    tokens.push(t);
+                }
+            }
+        }
+
+        // Every 3-gram with a moved symbol gets classified twice. Starting
+        // from scratch is cheaper if that's more than all of them.
+        let cut = prev.raw.trigram_cut;
+        let work: usize = tokens.iter().map(|&t| ts.symbol_trigrams(t)
+                .partition_point(|&(n, _, _)| n < cut)).sum();
+        if prev.raw.precision != 0.9 + precision * 0.1 ||
+                prev.bigram_lists.iter().any(Option::is_some) ||
+                work * 2 > cut {
+            return self.eval_layout(layout, ts, precision, false);
+        }
+
+        let mut scores = prev.clone();
+        scores.layout = *layout;
+        scores.constraints = self.eval_constraints(layout);
+
+        self.map_symbols(ts, &mut scores);
+        self.update_ngrams(ts, prev, &mut scores, &tokens);
+        self.calc_scores(ts, &mut scores);
+
+        scores
+    }
+    fn key_cost_ranking(&'a self) -> &'a [usize; NUM_KEYS] {&self.key_cost_ranking}
+    fn neighbor(&'a self, rng: &mut SmallRng, layout: &Layout) -> Layout {
+        let mut layout = *layout;
+        let op = rng.gen::<f64>() * 9.0;
+        if op < 8.0 { // Swap any random keys that exist on the layout
+            let (keys, n) = self.layout_keys(&layout);
+            if n < 2 {
+                return layout;
+            }
+            let r = rng.gen_range(0..(n * (n - 1)));
+            let (a, b) = (r / (n - 1), r % (n - 1));
+            let b = (a + b + 1) % n;
+            layout.swap(keys[a], keys[b]);
+        } else { // Swap fingers
+            let r = rng.gen_range(0..(8 * 7));
+            let (f0, f1) = (r / 7, r % 7);
+            let f1 = (f0 + f1 + 1) % 8;
+            let f0 = if f0 < Finger::Th as usize {f0} else {f0 + 1};
+            let f1 = if f1 < Finger::Th as usize {f1} else {f1 + 1};
+            // Only swap keys that exist on the layout and aren't pinned
+            let fk = |f: usize| -> Vec<usize> {
+                self.finger_keys[f].iter().map(|&k| k as usize)
+                                   .filter(|&k| layout[k][0] != '\0' &&
+                                                !self.is_pinned(&layout[k]))
+                                   .collect()
+            };
+            let (fk0, fk1) = (fk(f0), fk(f1));
+            let (l0, l1) = (fk0.len(), fk1.len());
+            let (r0, r1) = if l0 == l1 {
+                (0..l0, 0..l1)
+            } else if l0 < l1 {
+                let o = rng.gen_range(0..(l1 - l0 + 1));
+                (0..l0, o..(o + l0))
+            } else {
+                let o = rng.gen_range(0..(l0 - l1 + 1));
+                (o..(o + l1), 0..l1)
+            };
+            for (a, b) in r0.into_iter().zip(r1.into_iter()) {
+                layout.swap(fk0[a], fk1[b]);
+            }
+        }
+        layout
+    }
+    fn shuffle(&'a self, rng: &mut SmallRng, layout: &Layout) -> Layout {
+        let mut layout = *layout;
+        let (keys, n) = self.layout_keys(&layout);
+        let mut symbols: Vec<_> = keys[..n].iter().map(|&k| layout[k]).collect();
+
+        symbols.shuffle(rng);
+        for (&k, s) in keys[..n].iter().zip(symbols) {
+            layout[k] = s;
+        }
+        layout
+    }
+    fn is_symmetrical(&'a self) -> bool {
+        match self.params.board_type {
+            KeyboardType::ANSI | KeyboardType::Angle | KeyboardType::ISO |
+            KeyboardType::Custom => false,
+            _ => self.params.space_thumb == Hand::Any &&
+                 self.params.constraints.ref_layout == None &&
+                 self.params.constraints.pinned_keys.is_none() &&
+                 self.params.constraints.soft_pins.is_none() &&
+                 self.params.constraints.zxcv == 0.0 &&
+                 self.params.constraints.nonalpha == 0.0,
+        }
+    }
+}
+
+impl KuehlmakModel {
+    // Indexes of the keys that exist on the layout and aren't pinned and
+    // how many there are
+    fn layout_keys(&self, layout: &Layout) -> ([usize; NUM_KEYS], usize) {
+        let mut keys = [0; NUM_KEYS];
+        let mut n = 0;
+        for (k, key) in layout.iter().enumerate() {
+            if key[0] != '\0' && !self.is_pinned(key) {
+                keys[n] = k;
+                n += 1;
+            }
+        }
+        (keys, n)
+    }
+
+    // Pinned keys never move from their place in the initial layout
+    fn is_pinned(&self, key: &[char; 2]) -> bool {
+        self.params.constraints.pinned_keys.as_ref()
+            .is_some_and(|pinned| key.iter().any(|&c| pinned.contains(c)))
+    }
+
+    fn map_symbols(&self, ts: &TextStats, scores: &mut KuehlmakScores) {
+        let layout = scores.layout;
+        scores.token_keymap.clear();
         scores.token_keymap.resize(ts.token_base(), u8::MAX);
+        scores.strokes = 0;
+        scores.heatmap = [0; NUM_KEYS + 1];
+        let shift_key = self.params.shift_key.map(|p| p.key);
         for (k, symbols) in layout.iter().chain((&[[' ', '\0']]).iter())
                                   .enumerate() {
-            for &(count, token) in
-                    symbols.iter().filter_map(|s| ts.get_symbol([*s])) {
+            for (i, &(count, token)) in symbols.iter().enumerate()
+                    .filter_map(|(i, s)| ts.get_symbol([*s]).map(|x| (i, x))) {
                 scores.token_keymap[token] = k as u8;
                 scores.heatmap[k] += count;
                 scores.strokes += count;
+
+                // Shifted symbols also press Shift. Capital letters are
+                // lower-case in the corpus and use explicit Shift symbols.
+                if let (1, Some(sk)) = (i, shift_key) {
+                    scores.heatmap[sk] += count;
+                    scores.strokes += count;
+                }
             }
         }
+        if let Some((sk, &(count, token))) =
+                shift_key.zip(ts.get_symbol([SHIFT])) {
+            scores.token_keymap[token] = sk as u8;
+            scores.heatmap[sk] += count;
+            scores.strokes += count;
+        }
+    }
 
-        self.calc_effort(&mut scores);
-        self.calc_ngrams(ts, &mut scores, 0.9 + precision * 0.1);
-        self.score_travel(&mut scores);
-        self.score_imbalance(&mut scores);
+    fn calc_scores(&self, ts: &TextStats, scores: &mut KuehlmakScores) {
+        self.calc_effort(scores);
+        self.calc_ngrams(ts, scores);
+        self.score_travel(scores);
+        self.score_imbalance(scores);
 
         let strokes = scores.strokes as f64;
         let w = &self.params.weights;
@@ -863,53 +1666,8 @@ impl<'a> EvalModel<'a> for KuehlmakModel {
                 KuehlmakScores::get_wt_score(score, weight, t.factor,
                                              target.map(|x| x / 1000.0)))
          .sum::<f64>();
-
-        scores
-    }
-    fn key_cost_ranking(&'a self) -> &'a [usize; 30] {&self.key_cost_ranking}
-    fn neighbor(&'a self, rng: &mut SmallRng, layout: &Layout) -> Layout {
-        let mut layout = *layout;
-        let op = rng.gen::<f64>() * 9.0;
-        if op < 8.0 { // Swap any random keys
-            let r = rng.gen_range(0..(30 * 29));
-            let (a, b) = (r / 29, r % 29);
-            let b = (a + b + 1) % 30;
-            layout.swap(a, b);
-        } else { // Swap fingers
-            let r = rng.gen_range(0..(8 * 7));
-            let (f0, f1) = (r / 7, r % 7);
-            let f1 = (f0 + f1 + 1) % 8;
-            let f0 = if f0 < Finger::Th as usize {f0} else {f0 + 1};
-            let f1 = if f1 < Finger::Th as usize {f1} else {f1 + 1};
-            let (l0, l1) = (self.finger_keys[f0].len(), self.finger_keys[f1].len());
-            let (r0, r1) = if l0 == l1 {
-                (0..l0, 0..l1)
-            } else if l0 < l1 {
-                let o = rng.gen_range(0..(l1 - l0 + 1));
-                (0..l0, o..(o + l0))
-            } else {
-                let o = rng.gen_range(0..(l0 - l1 + 1));
-                (o..(o + l1), 0..l1)
-            };
-            for (a, b) in r0.into_iter().zip(r1.into_iter()) {
-                layout.swap(self.finger_keys[f0][a] as usize,
-                            self.finger_keys[f1][b] as usize);
-            }
-        }
-        layout
-    }
-    fn is_symmetrical(&'a self) -> bool {
-        match self.params.board_type {
-            KeyboardType::ANSI | KeyboardType::Angle | KeyboardType::ISO => false,
-            _ => self.params.space_thumb == Hand::Any &&
-                 self.params.constraints.ref_layout == None &&
-                 self.params.constraints.zxcv == 0.0 &&
-                 self.params.constraints.nonalpha == 0.0,
-        }
     }
-}
 
-impl KuehlmakModel {
     fn calc_effort(&self, scores: &mut KuehlmakScores) {
         // Simple effort model
         //
@@ -942,8 +1700,175 @@ impl KuehlmakModel {
                                    .sqrt() / scores.strokes as f64;
     }
 
-    fn calc_ngrams(&self, ts: &TextStats, scores: &mut KuehlmakScores,
-                   precision: f64) {
+    fn classify_bigram(&self, keymap: &[u8], [t0, t1, _]: [usize; 3])
+        -> Option<NGramClass>
+    {
+        let k0 = keymap[t0] as usize;
+        let k1 = keymap[t1] as usize;
+
+        if k0 > SPACE_KEY || k1 > SPACE_KEY {
+            return None;
+        }
+
+        let props = &self.key_props[k1];
+        if let Hand::Any = props.hand {return None}
+        let bigram_type = self.bigram_types[k0][k1] as usize;
+
+        // Correct travel estimate: going to k1 not from home position but
+        // from k0 instead.
+        let travel = if bigram_type == BIGRAM_SFB ||
+                        bigram_type == BIGRAM_SAMEKEY {
+            Self::fixed_travel(props.d_rel[k0]*4.0 - props.d_abs)
+        } else {
+            0
+        };
+        Some((bigram_type, props.hand as usize, props.finger as usize, travel))
+    }
+
+    fn classify_trigram(&self, keymap: &[u8], [t0, t1, t2]: [usize; 3])
+        -> Option<NGramClass>
+    {
+        let k0 = keymap[t0] as usize;
+        let k1 = keymap[t1] as usize;
+        let k2 = keymap[t2] as usize;
+
+        if k0 > SPACE_KEY || k1 > SPACE_KEY || k2 > SPACE_KEY {
+            return None;
+        }
+
+        let props = &self.key_props[k2];
+        if let Hand::Any = props.hand {return None}
+        let trigram_type = self.trigram_types[k0][k1][k2] as usize;
+
+        // Correct travel estimate: going to k2 not from home position but
+        // from k0 instead.
+        let travel = if (TRIGRAM_D_SAMEKEY..=TRIGRAM_SHD_SFB)
+                            .contains(&trigram_type) {
+            Self::fixed_travel(props.d_rel[k0]*2.0 - props.d_abs)
+        } else {
+            0
+        };
+        Some((trigram_type, props.hand as usize, props.finger as usize, travel))
+    }
+
+    fn fixed_travel(d: f32) -> i64 {
+        (d as f64 * TRAVEL_SCALE).round() as i64
+    }
+
+    fn add_bigram(raw: &mut NGramCounts, (bigram_type, hand, finger, travel): NGramClass,
+                  count: u64, add: bool) {
+        let travel = travel as i128 * count as i128;
+        if add {
+            raw.bigrams[bigram_type][hand] += count;
+            raw.bigram_travel[finger] += travel;
+            if bigram_type != BIGRAM_ALTERNATE {
+                raw.same_hand[hand] += count;
+            }
+        } else {
+            raw.bigrams[bigram_type][hand] -= count;
+            raw.bigram_travel[finger] -= travel;
+            if bigram_type != BIGRAM_ALTERNATE {
+                raw.same_hand[hand] -= count;
+            }
+        }
+    }
+
+    fn add_trigram(raw: &mut NGramCounts, (trigram_type, hand, finger, travel): NGramClass,
+                   count: u64, add: bool) {
+        let travel = travel as i128 * count as i128;
+        if add {
+            raw.trigrams[trigram_type][hand] += count;
+            raw.trigram_travel[finger] += travel;
+        } else {
+            raw.trigrams[trigram_type][hand] -= count;
+            raw.trigram_travel[finger] -= travel;
+        }
+    }
+
+    fn count_ngrams(&self, ts: &TextStats, scores: &mut KuehlmakScores,
+                    precision: f64) {
+        let raw = &mut scores.raw;
+        raw.precision = precision;
+
+        let percentile = (ts.total_bigrams() as f64 * precision) as u64;
+        for &(bigram, count, token) in ts.iter_bigrams() {
+            if raw.bigram_total > percentile {
+                break;
+            }
+            raw.bigram_total += count;
+            raw.bigram_cut += 1;
+
+            if let Some(c) = self.classify_bigram(&scores.token_keymap,
+                                                    ts.token_to_ngram(token)) {
+                Self::add_bigram(raw, c, count, true);
+                if let Some(v) = scores.bigram_lists[c.0].as_mut() {
+                    v.push((bigram, count))
+                }
+            }
+        }
+
+        let percentile = (ts.total_trigrams() as f64 * precision) as u64;
+        for &(trigram, count, token) in ts.iter_trigrams() {
+            if raw.trigram_total > percentile {
+                break;
+            }
+            raw.trigram_total += count;
+            raw.trigram_cut += 1;
+
+            if let Some(c) = self.classify_trigram(&scores.token_keymap,
+                                                     ts.token_to_ngram(token)) {
+                Self::add_trigram(raw, c, count, true);
+                if let Some(v) = scores.trigram_lists[c.0].as_mut() {
+                    v.push((trigram, count))
+                }
+            }
+        }
+    }
+
+    // Update the n-gram counts of prev for the symbols that moved to
+    // different keys in scores. Each affected n-gram is removed with its
+    // old classification and added back with the new one.
+    fn update_ngrams(&self, ts: &TextStats, prev: &KuehlmakScores,
+                     scores: &mut KuehlmakScores, tokens: &[usize]) {
+        let (old, new) = (&prev.token_keymap, &scores.token_keymap);
+        let raw = &mut scores.raw;
+
+        for (i, &t) in tokens.iter().enumerate() {
+            // N-grams with several moved symbols are only updated once,
+            // for the first of them
+            let seen = |ngram: &[usize; 3]|
+                tokens[..i].iter().any(|t| ngram.contains(t));
+
+            let cut = raw.bigram_cut;
+            for &(_, count, ngram) in ts.symbol_bigrams(t).iter()
+                                             .take_while(|&&(n, _, _)| n < cut) {
+                if seen(&ngram) {
+                    continue;
+                }
+                if let Some(c) = self.classify_bigram(old, ngram) {
+                    Self::add_bigram(raw, c, count, false);
+                }
+                if let Some(c) = self.classify_bigram(new, ngram) {
+                    Self::add_bigram(raw, c, count, true);
+                }
+            }
+            let cut = raw.trigram_cut;
+            for &(_, count, ngram) in ts.symbol_trigrams(t).iter()
+                                             .take_while(|&&(n, _, _)| n < cut) {
+                if seen(&ngram) {
+                    continue;
+                }
+                if let Some(c) = self.classify_trigram(old, ngram) {
+                    Self::add_trigram(raw, c, count, false);
+                }
+                if let Some(c) = self.classify_trigram(new, ngram) {
+                    Self::add_trigram(raw, c, count, true);
+                }
+            }
+        }
+    }
+
+    fn calc_ngrams(&self, ts: &TextStats, scores: &mut KuehlmakScores) {
         // Initial estimate of finger travel: from home position to key
         // neglecting the way back to home position, since that is just
         // relaxing the finger.
@@ -956,7 +1881,10 @@ impl KuehlmakModel {
         // Multiply the travel distance for same-finger bigrams and 3-grams
         // with a penalty factor that represents the finger travel speed
         // required.
+        let raw = &scores.raw;
+        let precision = raw.precision;
         let mut hand_total = [0u64; 3];
+        scores.finger_travel = [0.0; Finger::Num as usize];
         for (&count, props) in
                 scores.heatmap.iter().zip(self.key_props.iter()) {
             scores.finger_travel[props.finger as usize] +=
@@ -966,49 +1894,14 @@ impl KuehlmakModel {
         }
         let orig_finger_travel = scores.finger_travel;
 
-        let percentile = (ts.total_bigrams() as f64 * precision) as u64;
-        let mut total = 0;
-        let mut same_hand = [0u64; 2];
-        for &(bigram, count, token) in ts.iter_bigrams() {
-            if total > percentile {
-                break;
-            }
-            total += count;
-
-            let [t0, t1, _] = ts.token_to_ngram(token);
-            let k0 = scores.token_keymap[t0] as usize;
-            let k1 = scores.token_keymap[t1] as usize;
-
-            if k0 >= 31 || k1 >= 31 {
-                continue;
-            }
-
-            let props = &self.key_props[k1];
-            if let Hand::Any = props.hand {continue}
-            let bigram_type = self.bigram_types[k0][k1] as usize;
-
-            scores.bigram_counts[bigram_type][props.hand as usize] += count;
-            if let Some(v) = scores.bigram_lists[bigram_type].as_mut() {
-                v.push((bigram, count))
-            }
-
-            if bigram_type == BIGRAM_SFB || bigram_type == BIGRAM_SAMEKEY {
-                // Correct travel estimate: going to k1 not from home
-                // position but from k0 instead.
-                scores.finger_travel[props.finger as usize] +=
-                    (props.d_rel[k0]*4.0 - props.d_abs) as f64 * count as f64;
-            }
-
-            if bigram_type != BIGRAM_ALTERNATE {
-                same_hand[props.hand as usize] += count;
-            }
-        }
+        scores.bigram_counts = raw.bigrams;
         for count in scores.bigram_counts.iter_mut().flatten() {
             *count = ((*count as u128 * ts.total_bigrams() as u128)
-                      / total as u128) as u64;
+                      / raw.bigram_total as u128) as u64;
         }
-        for (travel, orig) in scores.finger_travel.iter_mut()
-                                    .zip(orig_finger_travel) {
+        for ((travel, orig), &correction) in scores.finger_travel.iter_mut()
+                .zip(orig_finger_travel).zip(raw.bigram_travel.iter()) {
+            *travel += correction as f64 / TRAVEL_SCALE;
             *travel += (*travel - orig) * (1.0 - precision);
         }
         let orig_finger_travel = scores.finger_travel;
@@ -1029,50 +1922,18 @@ impl KuehlmakModel {
         // Estimate same-hand runs as expected value of the geometic
         // distribution, which is 1 / "probability of switching hands".
         scores.hand_runs[0] = hand_total[0] as f64 /
-                             (hand_total[0] - same_hand[0]) as f64;
+                             (hand_total[0] - raw.same_hand[0]) as f64;
         scores.hand_runs[1] = hand_total[1] as f64 /
-                             (hand_total[1] - same_hand[1]) as f64;
-
-        let percentile = (ts.total_trigrams() as f64 * precision) as u64;
-        let mut total = 0;
-        for &(trigram, count, token) in ts.iter_trigrams() {
-            if total > percentile {
-                break;
-            }
-            total += count;
-
-            let [t0, t1, t2] = ts.token_to_ngram(token);
-            let k0 = scores.token_keymap[t0] as usize;
-            let k1 = scores.token_keymap[t1] as usize;
-            let k2 = scores.token_keymap[t2] as usize;
-
-            if k0 >= 31 || k1 >= 31 || k2 >= 31 {
-                continue;
-            }
+                             (hand_total[1] - raw.same_hand[1]) as f64;
 
-            let props = &self.key_props[k2];
-            if let Hand::Any = props.hand {continue}
-            let trigram_type = self.trigram_types[k0][k1][k2] as usize;
-
-            scores.trigram_counts[trigram_type][props.hand as usize] += count;
-            if let Some(v) = scores.trigram_lists[trigram_type].as_mut() {
-                v.push((trigram, count))
-            }
-
-            if trigram_type >= TRIGRAM_D_SAMEKEY &&
-                    trigram_type <= TRIGRAM_SHD_SFB {
-                // Correct travel estimate: going to k2 not from home
-                // position but from k0 instead.
-                scores.finger_travel[props.finger as usize] +=
-                    (props.d_rel[k0]*2.0 - props.d_abs) as f64 * count as f64;
-            }
-        }
+        scores.trigram_counts = raw.trigrams;
         for count in scores.trigram_counts.iter_mut().flatten() {
             *count = ((*count as u128 * ts.total_trigrams() as u128)
-                      / total as u128) as u64;
+                      / raw.trigram_total as u128) as u64;
         }
-        for (travel, orig) in scores.finger_travel.iter_mut()
-                                    .zip(orig_finger_travel) {
+        for ((travel, orig), &correction) in scores.finger_travel.iter_mut()
+                .zip(orig_finger_travel).zip(raw.trigram_travel.iter()) {
+            *travel += correction as f64 / TRAVEL_SCALE;
             *travel += (*travel - orig) * (1.0 - precision);
         }
 
@@ -1150,6 +2011,12 @@ impl KuehlmakModel {
                 .max(0.0) * (1.0 - params.ref_threshold) * params.ref_weight,
             _ => 0.0,
         };
+        if let Some(soft_pins) = params.soft_pins.as_ref() {
+            score += (self.displacement(soft_pins, layout,
+                                        params.soft_pin_keys.as_deref(),
+                                        &params.soft_pin_weights)
+                      - params.soft_pin_threshold).max(0.0);
+        }
         score += Self::eval_row(layout, 0, params.top_keys.as_deref()) *
             params.top_weight;
         score += Self::eval_row(layout, 1, params.mid_keys.as_deref()) *
@@ -1171,34 +2038,55 @@ impl KuehlmakModel {
     // How different are two layouts? Count how many symbols are on the same
     // key, finger and hand to make up a score between 0 (identical) and
     // 1 (as different as it gets).
+    pub fn layout_distance(&self, a: &Layout, b: &Layout) -> f64 {
+        self.displacement(a, b, None, &PinWeights::default())
+    }
+
+    // Fraction of symbols that are on different keys in two layouts
+    pub fn moved_symbols(&self, a: &Layout, b: &Layout) -> f64 {
+        self.displacement(a, b, None, &PinWeights {
+            same_key: 0.0,
+            same_finger: 1.0,
+            same_hand: 1.0,
+            other_hand: 1.0,
+        })
+    }
+
+    // Average penalty for symbols of pins (optionally only those in keys)
+    // that are not on the same key in layout
     #[allow(clippy::comparison_chain)]
-    fn layout_distance(&self, a: &Layout, b: &Layout) -> f64 {
+    fn displacement(&self, pins: &Layout, layout: &Layout, keys: Option<&str>,
+                    weights: &PinWeights) -> f64 {
         // Build indexed arrays of the lower-case symbols of both layouts
-        let mut i = 0usize;
-        let mut c = || {i += 1; ((i-1) as usize, a[i-1][0])};
-        let mut a = [c(), c(), c(), c(), c(), c(), c(), c(), c(), c(),
-                     c(), c(), c(), c(), c(), c(), c(), c(), c(), c(),
-                     c(), c(), c(), c(), c(), c(), c(), c(), c(), c()];
-        let mut i = 0usize;
-        let mut c = || {i += 1; ((i-1) as usize, b[i-1][0])};
-        let mut b = [c(), c(), c(), c(), c(), c(), c(), c(), c(), c(),
-                     c(), c(), c(), c(), c(), c(), c(), c(), c(), c(),
-                     c(), c(), c(), c(), c(), c(), c(), c(), c(), c()];
+        let mut a: [(usize, char); NUM_KEYS] = array::from_fn(|i| (i, pins[i][0]));
+        let mut b: [(usize, char); NUM_KEYS] = array::from_fn(|i| (i, layout[i][0]));
 
         // Sort them by symbol. If they don't match it'se because the layouts
         // implement different alphabets. Working on sorted arrays makes the
-        // rest of this function O(n)
+        // rest of this function O(n). Keys that don't exist sort first.
         a.sort_by_key(|x| x.1);
         b.sort_by_key(|x| x.1);
 
-        // Iterate over both array, evaluate distance of matching symbols
-        let mut i = 0;
-        let mut j = 0;
-        let mut distance = 120;
-        while i < 30 && j < 30 {
+        // Iterate over both array, evaluate distance of matching symbols.
+        // Pinned symbols missing from the layout count as other hand.
+        let is_pinned = |&(k, c): &(usize, char)|
+            c != '\0' && keys.is_none_or(|keys| pins[k].iter()
+                                                      .any(|&c| keys.contains(c)));
+        let n = a.iter().filter(|x| is_pinned(x)).count();
+        if n == 0 {
+            return 0.0;
+        }
+        let mut distance = 0.0;
+        let (mut i, mut j) = (0, 0);
+        while i < NUM_KEYS {
+            if !is_pinned(&a[i]) {
+                i += 1;
+                continue;
+            }
             // If the symbols don't match, advance the array with the smaller
             // symbol to try to resync them and find all matches
-            if a[i].1 < b[j].1 {
+            if j == NUM_KEYS || a[i].1 < b[j].1 {
+                distance += weights.other_hand;
                 i += 1;
                 continue;
             } else if a[i].1 > b[j].1 {
@@ -1206,19 +2094,20 @@ impl KuehlmakModel {
                 continue;
             }
             // Symbols match, adjust distance based on the indexes
-            if a[i].0 == b[j].0 {
-                distance -= 4; // same key
-            } else if self.key_props[a[i].0].finger ==
-                      self.key_props[b[j].0].finger {
-                distance -= 2;
-            } else if self.key_props[a[i].0].hand ==
-                      self.key_props[b[j].0].hand {
-                distance -= 1;
-            }
+            let (pa, pb) = (&self.key_props[a[i].0], &self.key_props[b[j].0]);
+            distance += if a[i].0 == b[j].0 {
+                weights.same_key
+            } else if pa.finger == pb.finger && pa.hand == pb.hand {
+                weights.same_finger
+            } else if pa.hand == pb.hand {
+                weights.same_hand
+            } else {
+                weights.other_hand
+            };
             i += 1;
             j += 1;
         }
-        distance as f64 / 120.0
+        distance / n as f64
     }
 
     // ZXCV-constraint: Penalize xzcv keys that are not in the left hand
@@ -1310,14 +2199,21 @@ impl KuehlmakModel {
 
     pub fn new(params: Option<KuehlmakParams>) -> KuehlmakModel {
         let params = params.unwrap_or_default();
-        let mut i = 0;
-        let mut k = || Self::key_props({i += 1; i - 1}, &params);
-        let key_props = [
-            k(), k(), k(), k(), k(), k(), k(), k(), k(), k(),
-            k(), k(), k(), k(), k(), k(), k(), k(), k(), k(),
-            k(), k(), k(), k(), k(), k(), k(), k(), k(), k(),
-            k()
-        ];
+        let mut key_props: [KeyProps; NUM_KEYS + 1] =
+            array::from_fn(|k| Self::key_props(k, &params));
+        if let KeyboardType::Custom = params.board_type {
+            Self::custom_key_props(&mut key_props, &params);
+        }
+
+        // Calculate relative distance to other keys on the same finger.
+        // Used for calculating finger travel distances.
+        for i in (0..NUM_KEYS).filter(|&k| !is_thumb_key(k)) {
+            for j in (0..NUM_KEYS).filter(|&k| !is_thumb_key(k)) {
+                let dx = key_props[j].x - key_props[i].x;
+                let dy = key_props[j].y - key_props[i].y;
+                key_props[i].d_rel[j] = (dx*dx + dy*dy).sqrt();
+            }
+        }
 
         // Scissors are symmetrical in two ways:
         // 1. If the bigram AB is a scissor, so is BA
@@ -1357,7 +2253,15 @@ impl KuehlmakModel {
         } else {
             scissors_lr.extend([(20u8, 1u8), (20, 2), (20, 3), (20, 4), (21, 4), (22, 4)]);
         }
-        let mut scissors = Vec::new();
+        // Scissors involving the number row or outer columns, and all
+        // scissors on custom boards are derived from the key geometry
+        let mut scissors = Self::geometric_scissors(&key_props);
+        if let KeyboardType::Custom = params.board_type {
+            scissors_lr.clear();
+        } else {
+            scissors.retain(|&(a, b)| a as usize >= NUMBER_ROW ||
+                                      b as usize >= NUMBER_ROW);
+        }
         scissors.extend(&scissors_lr);
         scissors.extend(scissors_lr.iter()
                                 .map(|b| (b.1, b.0)));
@@ -1367,7 +2271,7 @@ impl KuehlmakModel {
                                 .map(|b| (mirror_key(b.1), mirror_key(b.0))));
         scissors.sort();
 
-        let mut bigram_types = [[BIGRAM_ALTERNATE as u8; 31]; 31];
+        let mut bigram_types = [[BIGRAM_ALTERNATE as u8; NUM_KEYS + 1]; NUM_KEYS + 1];
         for (i, &KeyProps {hand: h0, finger: f0, is_stretch: s0, ..})
                 in key_props.iter().enumerate() {
             if let Hand::Any = h0 {continue}
@@ -1407,7 +2311,7 @@ impl KuehlmakModel {
             }
         }
 
-        let mut trigram_types = [[[TRIGRAM_NONE as u8; 31]; 31]; 31];
+        let mut trigram_types = [[[TRIGRAM_NONE as u8; NUM_KEYS + 1]; NUM_KEYS + 1]; NUM_KEYS + 1];
         for (i, &KeyProps {hand: h0, finger: f0, ..})
                 in key_props.iter().enumerate() {
             if let Hand::Any = h0 {continue}
@@ -1458,7 +2362,7 @@ impl KuehlmakModel {
             }
         }
 
-        let mut key_cost_ranking = [0; 30];
+        let mut key_cost_ranking = [0; NUM_KEYS];
         for (i, ranking) in key_cost_ranking.iter_mut().enumerate() {
             *ranking = i;
         }
@@ -1468,15 +2372,21 @@ impl KuehlmakModel {
             vec![], vec![], vec![], vec![], vec![],
             vec![], vec![], vec![], vec![],
         ];
-        // Enumerate keys symmetrically
-        for row in 0..3 {
+        // Enumerate keys symmetrically, number row and outer columns last
+        for row in (0..3).chain([-1]) {
             for col in 0..5 {
-                for i in [row * 10 + col, row * 10 + 9 - col] {
+                for i in [grid_key(row, col), grid_key(row, 9 - col)] {
                     let k = key_props[i];
                     finger_keys[k.finger as usize].push(i as u8);
                 }
             }
         }
+        for row in -1..3 {
+            for i in [grid_key(row, -1), grid_key(row, 10)] {
+                let k = key_props[i];
+                finger_keys[k.finger as usize].push(i as u8);
+            }
+        }
 
         KuehlmakModel {
             params,
@@ -1488,52 +2398,56 @@ impl KuehlmakModel {
         }
     }
 
-    fn key_props(key: u8, params: &KuehlmakParams) -> KeyProps {
-        let key = key as usize;
-        let row = key / 10;
-        let col = key % 10;
-        assert!(row < 3 || (row == 3 && col == 0));
-
-        let (hand, finger, weight, home_col, is_stretch) = match params.board_type {
-            _ if row == 3 => (params.space_thumb, Finger::Th, 0, 0.0, false),
-            KeyboardType::Hex | KeyboardType::HexStag if row == 0 => match col {
-                0     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, true),
-                1     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, false),
-                2     => (Hand::L, Finger::Lr, params.weights.ring_finger,   1.0, false),
-                3     => (Hand::L, Finger::Lm, params.weights.middle_finger, 2.0, false),
-                4     => (Hand::L, Finger::Li, params.weights.index_finger,  3.0, false),
-                5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
-                6     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
-                7     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
-                8     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
-                9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, true),
-                _     => panic!("col out of range"),
-            },
-            KeyboardType::Angle if row == 2 => match col {
-                0     => (Hand::L, Finger::Lr, params.weights.ring_finger,   0.0, false),
-                1     => (Hand::L, Finger::Lm, params.weights.middle_finger, 1.0, false),
-                2     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, false),
-                3     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, true),
-                4     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, true),
-                5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, true),
-                6     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
-                7     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
-                8     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
-                9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
-                _     => panic!("col out of range"),
-            },
-            _ => match col {
-                0     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, false),
-                1     => (Hand::L, Finger::Lr, params.weights.ring_finger,   1.0, false),
-                2     => (Hand::L, Finger::Lm, params.weights.middle_finger, 2.0, false),
-                3     => (Hand::L, Finger::Li, params.weights.index_finger,  3.0, false),
-                4     => (Hand::L, Finger::Li, params.weights.index_finger,  3.0, true),
-                5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, true),
-                6     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
-                7     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
-                8     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
-                9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
-                _     => panic!("col out of range"),
+    fn key_props(key: usize, params: &KuehlmakParams) -> KeyProps {
+        if is_thumb_key(key) {
+            return Self::thumb_key_props(key, params);
+        }
+        let (row, col) = grid_row_col(key);
+
+        // Outer columns are lateral stretches of the pinkies
+        let (hand, finger, weight, home_col, is_stretch) = match col {
+            -1 => (Hand::L, Finger::Lp, params.weights.pinky_finger, 0.0, true),
+            10 => (Hand::R, Finger::Rp, params.weights.pinky_finger, 9.0, true),
+            _ => match params.board_type {
+                KeyboardType::Hex | KeyboardType::HexStag if row == 0 => match col {
+                    0     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, true),
+                    1     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, false),
+                    2     => (Hand::L, Finger::Lr, params.weights.ring_finger,   1.0, false),
+                    3     => (Hand::L, Finger::Lm, params.weights.middle_finger, 2.0, false),
+                    4     => (Hand::L, Finger::Li, params.weights.index_finger,  3.0, false),
+                    5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
+                    6     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
+                    7     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
+                    8     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
+                    9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, true),
+                    _     => panic!("col out of range"),
+                },
+                KeyboardType::Angle if row == 2 => match col {
+                    0     => (Hand::L, Finger::Lr, params.weights.ring_finger,   0.0, false),
+                    1     => (Hand::L, Finger::Lm, params.weights.middle_finger, 1.0, false),
+                    2     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, false),
+                    3     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, true),
+                    4     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, true),
+                    5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, true),
+                    6     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
+                    7     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
+                    8     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
+                    9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
+                    _     => panic!("col out of range"),
+                },
+                _ => match col {
+                    0     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, false),
+                    1     => (Hand::L, Finger::Lr, params.weights.ring_finger,   1.0, false),
+                    2     => (Hand::L, Finger::Lm, params.weights.middle_finger, 2.0, false),
+                    3     => (Hand::L, Finger::Li, params.weights.index_finger,  3.0, false),
+                    4     => (Hand::L, Finger::Li, params.weights.index_finger,  3.0, true),
+                    5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, true),
+                    6     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
+                    7     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
+                    8     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
+                    9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
+                    _     => panic!("col out of range"),
+                },
             },
         };
         let (key_offsets, key_cost) = match params.board_type {
@@ -1544,42 +2458,129 @@ impl KuehlmakModel {
             KeyboardType::ANSI    => (&KEY_OFFSETS_ANSI, &KEY_COST_ANSI),
             KeyboardType::Angle   => (&KEY_OFFSETS_ANGLE, &KEY_COST_ANGLE),
             KeyboardType::ISO     => (&KEY_OFFSETS_ISO, &KEY_COST_ISO),
+            KeyboardType::Custom  => (&KEY_OFFSETS_ORTHO, &KEY_COST_ORTHO),
         };
-        let h = match hand {
-            Hand::Any => 0usize,
-            _         => hand as usize,
+        let h = hand as usize;
+        let cost = match (row, col) {
+            (_, -1) | (_, 10) => KEY_COST_OUTER[(row + 1) as usize],
+            (-1, _)           => KEY_COST_NUMBER[col as usize],
+            _                 => key_cost[key],
         };
 
-        // Weigh horizontal offset more severely (factor 1.5).
-        let x = col as f32 - home_col + key_offsets[row][h];
-        let y = if row == 3 {0.0} else {row as f32 - 1.0};
-        let d_abs = (x*x + y*y).sqrt();
+        // Key position relative to the left end of the home row
+        let x = col as f32 + key_offsets[(row + 1) as usize][h];
+        let y = row as f32 - 1.0;
+        let d_abs = ((x - home_col) * (x - home_col) + y*y).sqrt();
 
-        // Calculate relative distance to other keys on the same finger.
-        // Used for calculating finger travel distances.
-        let mut d_rel = [-1.0; 31];
-        d_rel[key] = 0.0;
+        KeyProps {
+            hand,
+            finger,
+            is_stretch,
+            x, y,
+            d_abs,
+            d_rel: [-1.0; NUM_KEYS + 1],
+            cost: cost as u16 * weight as u16,
+        }
+    }
 
-        let mut calc_d_rel = |r: usize, c: usize| {
-            let dx = c as f32 - col as f32 + key_offsets[r][h] - key_offsets[row][h];
-            let dy = r as f32 - row as f32;
-            d_rel[r * 10 + c] = (dx*dx + dy*dy).sqrt();
+    // Apply the key declarations of a KeyboardType::Custom board. Keys that
+    // are not declared keep their Ortho properties. The home position of each
+    // finger is its cheapest key in the home row. Keys that are at least 3/4
+    // of a key width to the side of it are stretch keys unless declared
+    // otherwise.
+    fn custom_key_props(key_props: &mut [KeyProps; NUM_KEYS + 1],
+                        params: &KuehlmakParams) {
+        let w = &params.weights;
+        for CustomKey {key, def} in params.custom_keys.iter() {
+            let weight = match def.finger {
+                Finger::Lp | Finger::Rp => w.pinky_finger,
+                Finger::Lr | Finger::Rr => w.ring_finger,
+                Finger::Lm | Finger::Rm => w.middle_finger,
+                Finger::Li | Finger::Ri => w.index_finger,
+                _                       => w.thumb_finger,
+            };
+            key_props[*key] = KeyProps {
+                hand: def.hand.unwrap_or(Hand::Any),
+                finger: def.finger,
+                x: def.x,
+                y: def.y,
+                cost: def.cost as u16 * weight as u16,
+                ..key_props[*key]
+            };
+        }
+        for key in (0..NUM_KEYS).filter(|&k| !is_thumb_key(k)) {
+            let KeyProps {hand, finger, x, y, ..} = key_props[key];
+            let same_finger = |&k: &usize| key_props[k].hand == hand &&
+                                           key_props[k].finger == finger;
+            let home = (-1..=10).map(|col| grid_key(1, col))
+                                .filter(same_finger)
+                               .min_by_key(|&k| key_props[k].cost)
+                               .unwrap_or(key);
+            let dx = x - key_props[home].x;
+            let dy = y - key_props[home].y;
+            let stretch = params.custom_keys.iter()
+                                .find(|c| c.key == key)
+                                .and_then(|c| c.def.stretch);
+
+            key_props[key].d_abs = (dx*dx + dy*dy).sqrt();
+            key_props[key].is_stretch = stretch.unwrap_or(dx.abs() >= 0.75);
+        }
+    }
+
+    // Derive scissors from the key geometry: same-hand bigrams on different
+    // fingers that are at least 1.5 rows apart, or where the pinky has to
+    // reach more than half a row higher than the ring or middle finger.
+    fn geometric_scissors(key_props: &[KeyProps; NUM_KEYS + 1]) -> Vec<(u8, u8)> {
+        // Count fingers from the outside, starting with 0 for the pinky
+        let rank = |f: Finger| match f {
+            Finger::Lp | Finger::Rp => 0,
+            Finger::Lr | Finger::Rr => 1,
+            Finger::Lm | Finger::Rm => 2,
+            _                       => 3,
         };
-        for r in 0..3 {
-            for c in 0..10 {
-                if r != row || c != col {
-                    calc_d_rel(r, c);
+        let mut scissors = Vec::new();
+        for (i, p0) in key_props.iter().enumerate() {
+            for (j, p1) in key_props.iter().enumerate() {
+                if p0.hand != p1.hand || p0.finger == p1.finger ||
+                        is_thumb_key(i) || is_thumb_key(j) {
+                    continue;
+                }
+                let dy = p1.y - p0.y;
+                if dy.abs() >= 1.5 ||
+                   rank(p0.finger) == 0 && rank(p1.finger) < 3 && dy > 0.5 ||
+                   rank(p1.finger) == 0 && rank(p0.finger) < 3 && dy < -0.5 {
+                    scissors.push((i as u8, j as u8));
                 }
             }
         }
-        calc_d_rel(3, 0);
+        scissors
+    }
+
+    // Thumb keys, including Space, don't contribute to finger travel.
+    // Moving the thumb between keys is scored through key costs and n-gram
+    // statistics instead.
+    fn thumb_key_props(key: usize, params: &KuehlmakParams) -> KeyProps {
+        let (hand, cost) = match key {
+            SPACE_KEY => (params.space_thumb, 0),
+            k if k < RTHUMB => (Hand::L, KEY_COST_THUMB[k - LTHUMB]),
+            k => (Hand::R, KEY_COST_THUMB[k - RTHUMB]),
+        };
+
+        // Rough positions around Space for visualization
+        let (x, y) = match key {
+            SPACE_KEY => (4.5, 2.0),
+            k if k < RTHUMB => (3.5 - (k - LTHUMB) as f32, 2.0),
+            k => (5.5 + (k - RTHUMB) as f32, 2.0),
+        };
 
         KeyProps {
             hand,
-            finger,
-            is_stretch,
-            d_abs, d_rel,
-            cost: key_cost[key] as u16 * weight as u16,
+            finger: Finger::Th,
+            is_stretch: false,
+            x, y,
+            d_abs: 0.0,
+            d_rel: [0.0; NUM_KEYS + 1],
+            cost: cost as u16 * params.weights.thumb_finger as u16,
         }
     }
 }
@@ -1595,6 +2596,12 @@ const BIGRAM_SCISSOR:    usize = 7;
 const BIGRAM_SFB:        usize = 8;
 const BIGRAM_NUM_TYPES:  usize = 9;
 
+const BIGRAM_NAMES: [&str; BIGRAM_NUM_TYPES] = ["", "DRolls", "URolls",
+    "SameKey",
+    "LSB3s (count as 1/3 WLSBs, 2/3 URolls)",
+    "LSB2s (count as 1/2 WLSBs, 1/2 URolls)",
+    "LSB1s", "Scissors", "SFBs"];
+
 const TRIGRAM_NONE:        usize = 0;
 const TRIGRAM_D_SAMEKEY:   usize = 1;
 const TRIGRAM_SHD_SAMEKEY: usize = 2;
@@ -1611,53 +2618,289 @@ const TRIGRAM_REDIRECT:    usize = 12;
 const TRIGRAM_CONTORT:     usize = 13;
 const TRIGRAM_NUM_TYPES:   usize = 14;
 
+const TRIGRAM_NAMES: [&str; TRIGRAM_NUM_TYPES] = ["",
+    "dSameKey", "shdSameKey (count as Redirects)",
+    "dSFBs", "shdSFBs (count as Contorts)", "dDRolls", "dURolls",
+    "dLSB3s (count as 1/3 dWLSBs, 2/3 dUROLLS)",
+    "dLSB2s (count as 1/2 dWLSBs, 1/2 dURolls)",
+    "dLSB1s", "dScissors", "RRolls", "Redirects", "Contortions"];
+
 
+// Horizontal offsets of the number, top, home and bottom rows
 type KeyOffsets = [[f32; 2]; 4];
 
-const KEY_OFFSETS_ORTHO: KeyOffsets = [[ 0.0,   0.0 ], [0.0, 0.0], [ 0.0, 0.0], [0.0, 0.0]];
-const KEY_OFFSETS_HEX:   KeyOffsets = [[-1.0,   1.0 ], [0.0, 0.0], [ 0.0, 0.0], [0.0, 0.0]];
-const KEY_OFFSETS_ANSI:  KeyOffsets = [[-0.25, -0.25], [0.0, 0.0], [ 0.5, 0.5], [0.0, 0.0]];
-const KEY_OFFSETS_ANGLE: KeyOffsets = [[-0.25, -0.25], [0.0, 0.0], [-0.5, 0.5], [0.0, 0.0]];
-const KEY_OFFSETS_ISO:   KeyOffsets = [[-0.25, -0.25], [0.0, 0.0], [-0.5, 0.5], [0.0, 0.0]];
-const KEY_COST_ORTHO: [u8; 31] = [
+const KEY_OFFSETS_ORTHO: KeyOffsets = [[ 0.0,   0.0 ], [ 0.0,   0.0 ], [0.0, 0.0], [ 0.0, 0.0]];
+const KEY_OFFSETS_HEX:   KeyOffsets = [[-0.5,   0.5 ], [-1.0,   1.0 ], [0.0, 0.0], [ 0.0, 0.0]];
+const KEY_OFFSETS_ANSI:  KeyOffsets = [[-0.75, -0.75], [-0.25, -0.25], [0.0, 0.0], [ 0.5, 0.5]];
+const KEY_OFFSETS_ANGLE: KeyOffsets = [[-0.75, -0.75], [-0.25, -0.25], [0.0, 0.0], [-0.5, 0.5]];
+const KEY_OFFSETS_ISO:   KeyOffsets = [[-0.75, -0.75], [-0.25, -0.25], [0.0, 0.0], [-0.5, 0.5]];
+// Number row and outer columns (number, top, home, bottom row) are the same
+// on all built-in board types
+const KEY_COST_NUMBER: [u8; 10] = [
+   12,  8,  6,  8, 16, 16,  8,  6,  8, 12,
+];
+const KEY_COST_OUTER: [u8; 4] = [16, 8, 4, 8];
+const KEY_COST_ORTHO: [u8; 30] = [
     4,  2,  2,  4, 12, 12,  4,  2,  2,  4,
     1,  1,  1,  1,  3,  3,  1,  1,  1,  1,
     2,  4,  4,  2,  6,  6,  2,  4,  4,  2,
-                        1
 ];
-const KEY_COST_COL_STAG: [u8; 31] = [
+const KEY_COST_COL_STAG: [u8; 30] = [
     2,  2,  2,  2,  6,  6,  2,  2,  2,  2,
     1,  1,  1,  1,  3,  3,  1,  1,  1,  1,
     2,  2,  2,  2,  6,  6,  2,  2,  2,  2,
-                        1
 ];
-const KEY_COST_HEX: [u8; 31] = [
+const KEY_COST_HEX: [u8; 30] = [
     3,  4,  2,  2,  4,      4,  2,  2,  4,  3,
       1,  1,  1,  1,  3,  3,  1,  1,  1,  1,
     2,  4,  4,  2,  6,      6,  2,  4,  4,  2,
-                          1
 ];
-const KEY_COST_HEX_STAG: [u8; 31] = [
+const KEY_COST_HEX_STAG: [u8; 30] = [
     2,  3,  2,  2,  2,      2,  2,  2,  3,  2,
       1,  1,  1,  1,  3,  3,  1,  1,  1,  1,
     2,  2,  2,  2,  6,      6,  2,  2,  2,  2,
-                          1
 ];
-const KEY_COST_ANSI: [u8; 31] = [
+const KEY_COST_ANSI: [u8; 30] = [
     4,  2,  2,  4,  6, 12,  4,  2,  2,  4,
      1,  1,  1,  1,  3,  3,  1,  1,  1,  1,
        2,  4,  4,  2,  9,  3,  2,  4,  4,  2,
-                         1
 ];
-const KEY_COST_ANGLE: [u8; 31] = [
+const KEY_COST_ANGLE: [u8; 30] = [
     4,  2,  2,  4,  6, 12,  4,  2,  2,  4,
      1,  1,  1,  1,  3,  3,  1,  1,  1,  1,
        4,  4,  2,  3, 12,  3,  2,  4,  4,  2,
-                         1
 ];
-const KEY_COST_ISO: [u8; 31] = [
+const KEY_COST_ISO: [u8; 30] = [
      4,  2,  2,  4,  6, 12,  4,  2,  2,  4,
       1,  1,  1,  1,  3,  3,  1,  1,  1,  1,
     2,  4,  4,  2,  3,      3,  2,  4,  4,  2,
-                          1
 ];
+const KEY_COST_THUMB: [u8; THUMB_KEYS] = [1, 2, 3];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static THUMB_LAYOUT: &str =
+"  q  w  f  p  b  j  l  u  y ;:
+  a  r  s  t  g  m  n  i  o '\"
+  z  x  c  d  v  k  h ,< .> /?
+          -_  e | =+
+";
+
+    // Check that thumb keys are numbered from the center outwards and
+    // survive a round trip through the layout string
+    #[test]
+    fn thumb_keys() {
+        let layout = layout_from_str(THUMB_LAYOUT).unwrap();
+
+        assert_eq!(layout[LTHUMB], ['e', 'E']);
+        assert_eq!(layout[LTHUMB + 1], ['-', '_']);
+        assert_eq!(layout[LTHUMB + 2], ['\0', '\0']);
+        assert_eq!(layout[RTHUMB], ['=', '+']);
+        assert_eq!(layout[RTHUMB + 1], ['\0', '\0']);
+        assert_eq!(layout_to_str(&layout), THUMB_LAYOUT);
+    }
+
+    // Check that lines following the key rows are only parsed as thumb
+    // rows if they look like one
+    #[test]
+    fn no_thumb_keys() {
+        let text = "  q  w  e  r  t  y  u  i  o  p
+  a  s  d  f  g  h  j  k  l ;:
+  z  x  c  v  b  n  m ,< .> /?
+Score+Con 4270.2    +0.0 = 4270.2 |   [Q] [W] [E] [R] [T]| [Y] [U] [I] [O] [P]
+";
+        let layout = layout_from_str(text).unwrap();
+
+        assert!(layout[LTHUMB..].iter().all(|&[a, b]| a == '\0' && b == '\0'));
+        assert!(layout_from_str(&THUMB_LAYOUT.replace("=+", "=+ [{ ]} \\|"))
+                .is_err());
+    }
+
+    // Undeclared keys on a custom board behave like Ortho, declared keys
+    // take their geometry from the config
+    #[test]
+    fn custom_keys() {
+        let params: KuehlmakParams = toml::from_str("
+board_type = 'Custom'
+[[custom_keys]]
+row = 'Top'
+col = 0
+x = 0.0
+y = -0.5
+finger = 'Lp'
+cost = 3
+[[custom_keys]]
+row = 'Thumb'
+col = 3
+x = 5.5
+y = 2.5
+finger = 'Th'
+cost = 2
+").unwrap();
+        let custom = KuehlmakModel::new(Some(params));
+        let ortho = KuehlmakModel::new(None);
+
+        assert_eq!(custom.key_props[0].cost,
+                   3 * custom.params.weights.pinky_finger as u16);
+        assert_eq!(custom.key_props[0].d_abs, 0.5);
+        assert_eq!(custom.key_props[10].d_rel[0], 0.5);
+        assert!(custom.key_props[RTHUMB].hand == Hand::R);
+        assert_eq!(custom.key_props[RTHUMB].cost, 2);
+        for k in 1..LTHUMB {
+            assert_eq!(custom.key_props[k].cost, ortho.key_props[k].cost);
+            assert_eq!(custom.key_props[k].d_abs, ortho.key_props[k].d_abs);
+            assert_eq!(custom.key_props[k].is_stretch,
+                       ortho.key_props[k].is_stretch);
+        }
+        // Pinky top row to ring finger home row is no longer a scissor
+        assert_ne!(custom.bigram_types[0][11], BIGRAM_SCISSOR as u8);
+        assert_eq!(ortho.bigram_types[0][11], BIGRAM_SCISSOR as u8);
+
+        assert!(toml::from_str::<KuehlmakParams>("
+[[custom_keys]]
+row = 'Home'
+col = 11
+x = 11.0
+y = 0.0
+finger = 'Rp'
+cost = 3
+").is_err());
+    }
+
+    // Number row and outer columns map to their own keys and survive a
+    // round trip. 11-key rows only have a right outer column.
+    #[test]
+    fn wide_layouts() {
+        let text =
+"    1! 2@ 3# 4$ 5% 6^ 7& 8* 9( 0)
+ `~  q  w  f  p  b  j  l  u  y ;: -_
+ \\|  a  r  s  t  g  m  n  i  o '\" =+
+ [{  z  x  c  d  v  k  h ,< .> /? ]}
+";
+        let layout = layout_from_str(text).unwrap();
+        assert_eq!(layout[NUMBER_ROW], ['1', '!']);
+        assert_eq!(layout[grid_key(0, -1)], ['`', '~']);
+        assert_eq!(layout[grid_key(2, 10)], [']', '}']);
+        assert_eq!(layout[10], ['a', 'A']);
+        assert_eq!(layout_to_str(&layout), text);
+        assert_eq!(layout_from_str(&layout_to_str(&mirror_layout(&layout))),
+                   Ok(mirror_layout(&layout)));
+
+        let layout = layout_from_str(
+"  q  w  f  p  b  j  l  u  y ;: -_
+  a  r  s  t  g  m  n  e  i  o '\"
+  z  x  c  d  v  k  h ,< .> /?
+").unwrap();
+        assert_eq!(layout[grid_key(0, 10)], ['-', '_']);
+        assert_eq!(layout[grid_key(1, 10)], ['\'', '"']);
+        assert!(layout[NUMBER_ROW..].iter().filter(|&&[a, _]| a != '\0')
+                                   .count() == 2);
+
+        let model = KuehlmakModel::new(None);
+        let k = grid_key(1, 10);
+        assert!(model.key_props[k].finger == Finger::Rp);
+        assert!(model.key_props[k].is_stretch);
+        assert!(model.key_props[k].cost > model.key_props[19].cost);
+    }
+
+    // Incremental evaluation of neighbors must give exactly the same
+    // scores as evaluating them from scratch
+    #[test]
+    fn eval_delta() {
+        use rand::SeedableRng;
+
+        let params: KuehlmakParams =
+            toml::from_str("shift_key = { row = 'Bottom', col = 0 }").unwrap();
+        let model = KuehlmakModel::new(Some(params));
+        let text = TextStats::from_text("The quick brown fox jumps over the \
+            lazy dog. Sphinx of black quartz, judge my vow! How vexingly \
+            quick daft zebras jump; \"WALTZ, bad nymph, for quick jigs vex.\"",
+            true).unwrap();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut layout = layout_from_str(THUMB_LAYOUT).unwrap();
+
+        for precision in [0.0, 0.5, 1.0] {
+            let mut scores = model.eval_layout(&layout, &text, precision, false);
+            for _ in 0..200 {
+                layout = model.neighbor(&mut rng, &layout);
+                let full = model.eval_layout(&layout, &text, precision, false);
+                scores = model.eval_layout_delta(&scores, &layout, &text,
+                                                 precision);
+                assert_eq!(scores.layout, full.layout);
+                assert_eq!(scores.heatmap, full.heatmap);
+                assert_eq!(scores.bigram_counts, full.bigram_counts);
+                assert_eq!(scores.trigram_counts, full.trigram_counts);
+                for (a, b) in scores.get_scores().iter()
+                                    .zip(full.get_scores().iter()) {
+                    assert_eq!(a.to_bits(), b.to_bits());
+                }
+                assert_eq!(scores.total().to_bits(), full.total().to_bits());
+            }
+        }
+    }
+
+    // Pinned keys must never move, not even in finger swaps
+    #[test]
+    fn pinned_keys() {
+        use rand::SeedableRng;
+
+        let params: KuehlmakParams =
+            toml::from_str("constraints = { pinned_keys = 'zxcv,.' }").unwrap();
+        let model = KuehlmakModel::new(Some(params));
+        let mut rng = SmallRng::seed_from_u64(0);
+        let initial = layout_from_str(THUMB_LAYOUT).unwrap();
+        let pinned = [20, 21, 22, 24, 27, 28];
+
+        let mut layout = model.shuffle(&mut rng, &initial);
+        for _ in 0..1000 {
+            for &k in pinned.iter() {
+                assert_eq!(layout[k], initial[k]);
+            }
+            layout = model.neighbor(&mut rng, &layout);
+        }
+        assert_ne!(layout, initial);
+    }
+
+    // Soft pins penalize symbols by how far they moved from their pins
+    #[test]
+    fn soft_pins() {
+        let qwerty = "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?\n";
+        let params: KuehlmakParams = toml::from_str(&format!("
+[constraints]
+soft_pins = '''
+{}'''
+soft_pin_keys = 'qae'
+soft_pin_threshold = 0.5
+soft_pin_weights = {{ same_finger = 1.0, same_hand = 2.0, other_hand = 4.0 }}
+", qwerty)).unwrap();
+        let model = KuehlmakModel::new(Some(params));
+        let mut layout = layout_from_str(qwerty).unwrap();
+
+        assert_eq!(model.eval_constraints(&layout), 0.0);
+        layout.swap(0, 10); // q and a on the same finger
+        layout.swap(2, 7);  // e to the other hand
+        assert_eq!(model.eval_constraints(&layout), 1.5);
+        assert_eq!(model.layout_distance(&layout, &layout), 0.0);
+    }
+
+    // Soft pins depend on the orientation of the layout, so a layout with
+    // '.' on the left hand must not be mirrored
+    #[test]
+    fn soft_pins_not_mirrored() {
+        let mirrored = "p o i u y t r e w q\n:; l k j h g f d s a\n?/ >. <, m n b v c x z\n";
+        let params: KuehlmakParams = toml::from_str(&format!("
+[constraints]
+soft_pins = '''
+{}'''
+", mirrored)).unwrap();
+        let model = KuehlmakModel::new(Some(params));
+        let text = TextStats::from_text("the quick brown fox", false).unwrap();
+        let layout = layout_from_str(mirrored).unwrap();
+
+        let scores = model.eval_layout(&layout, &text, 1.0, false);
+        assert_eq!(model.eval_constraints(&layout), 0.0);
+        assert!(scores.layout() == layout);
+    }
+}
@@ -0,0 +1,495 @@
+use super::{Layout, KeyboardType};
+use super::eval::{is_thumb_key, grid_key, grid_row_col,
+                  LTHUMB, RTHUMB, NUMBER_ROW};
+use std::fmt::Write;
+
+// Key as it is written in a layout file, for error messages
+pub(crate) fn key_name([a, b]: [char; 2]) -> String {
+    match b.to_lowercase().next() {
+        Some(l) if l == a => b.to_string(),
+        _ => [a, b].into_iter().filter(|&c| c != '\0').collect(),
+    }
+}
+
+// XKB keycode of a key in the layout. On ISO boards the bottom row of the
+// left hand is shifted by one key for the angle mod, which makes use of
+// the extra key left of Z and leaves the B key unused.
+pub(crate) fn xkb_keycode(key: usize, board_type: KeyboardType) -> Option<String> {
+    if is_thumb_key(key) {
+        return None;
+    }
+    let iso = matches!(board_type, KeyboardType::ISO);
+    let (row, col) = grid_row_col(key);
+    match (row, col) {
+        (-1, -1) => Some("TLDE".to_string()),
+        (-1, 0..=10) => Some(format!("AE{:02}", col + 1)),
+        (0, 0..=10) => Some(format!("AD{:02}", col + 1)),
+        (1, 0..=10) => Some(format!("AC{:02}", col + 1)),
+        (2, 0) if iso => Some("LSGT".to_string()),
+        (2, 1..=4) if iso => Some(format!("AB{:02}", col)),
+        (2, 0..=9) => Some(format!("AB{:02}", col + 1)),
+        (2, -1) => match board_type {
+            KeyboardType::ANSI | KeyboardType::Angle |
+            KeyboardType::ISO => None,
+            _ => Some("LSGT".to_string()),
+        },
+        _ => None,
+    }
+}
+
+// XKB keysym names of ASCII punctuation
+pub(crate) const ASCII_KEYSYMS: [(char, &str); 33] = [
+    (' ', "space"), ('!', "exclam"), ('"', "quotedbl"), ('#', "numbersign"),
+    ('$', "dollar"), ('%', "percent"), ('&', "ampersand"),
+    ('\'', "apostrophe"), ('(', "parenleft"), (')', "parenright"),
+    ('*', "asterisk"), ('+', "plus"), (',', "comma"), ('-', "minus"),
+    ('.', "period"), ('/', "slash"), (':', "colon"), (';', "semicolon"),
+    ('<', "less"), ('=', "equal"), ('>', "greater"), ('?', "question"),
+    ('@', "at"), ('[', "bracketleft"), ('\\', "backslash"),
+    (']', "bracketright"), ('^', "asciicircum"), ('_', "underscore"),
+    ('`', "grave"), ('{', "braceleft"), ('|', "bar"), ('}', "braceright"),
+    ('~', "asciitilde"),
+];
+
+// XKB keysym names of Latin-1 symbols U+00A0 to U+00FF
+pub(crate) const LATIN1_KEYSYMS: [&str; 96] = [
+    "nobreakspace", "exclamdown", "cent", "sterling", "currency", "yen",
+    "brokenbar", "section", "diaeresis", "copyright", "ordfeminine",
+    "guillemotleft", "notsign", "hyphen", "registered", "macron",
+    "degree", "plusminus", "twosuperior", "threesuperior", "acute", "mu",
+    "paragraph", "periodcentered", "cedilla", "onesuperior", "masculine",
+    "guillemotright", "onequarter", "onehalf", "threequarters",
+    "questiondown",
+    "Agrave", "Aacute", "Acircumflex", "Atilde", "Adiaeresis", "Aring",
+    "AE", "Ccedilla", "Egrave", "Eacute", "Ecircumflex", "Ediaeresis",
+    "Igrave", "Iacute", "Icircumflex", "Idiaeresis", "ETH", "Ntilde",
+    "Ograve", "Oacute", "Ocircumflex", "Otilde", "Odiaeresis", "multiply",
+    "Oslash", "Ugrave", "Uacute", "Ucircumflex", "Udiaeresis", "Yacute",
+    "THORN", "ssharp",
+    "agrave", "aacute", "acircumflex", "atilde", "adiaeresis", "aring",
+    "ae", "ccedilla", "egrave", "eacute", "ecircumflex", "ediaeresis",
+    "igrave", "iacute", "icircumflex", "idiaeresis", "eth", "ntilde",
+    "ograve", "oacute", "ocircumflex", "otilde", "odiaeresis", "division",
+    "oslash", "ugrave", "uacute", "ucircumflex", "udiaeresis", "yacute",
+    "thorn", "ydiaeresis",
+];
+
+fn xkb_keysym(c: char) -> String {
+    match c {
+        '\0' => "NoSymbol".to_string(),
+        'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_string(),
+        '\u{a0}'..='\u{ff}' => LATIN1_KEYSYMS[c as usize - 0xa0].to_string(),
+        _ => match ASCII_KEYSYMS.iter().find(|&&(s, _)| s == c) {
+            Some(&(_, name)) => name.to_string(),
+            None => format!("U{:04X}", c as u32),
+        },
+    }
+}
+
+// XKB symbols file with a single layout called "basic". Keys that are not
+// part of the layout keep their symbols from the US layout.
+pub fn layout_to_xkb(layout: &Layout, board_type: KeyboardType, name: &str)
+    -> Result<String, String>
+{
+    let mut xkb = String::new();
+    writeln!(xkb, "// {} keyboard layout generated by Kühlmak", name).unwrap();
+    writeln!(xkb, "default partial alphanumeric_keys").unwrap();
+    writeln!(xkb, "xkb_symbols \"basic\" {{").unwrap();
+    writeln!(xkb, "    include \"us(basic)\"").unwrap();
+    writeln!(xkb, "    name[Group1] = \"{}\";", name.replace('"', "'")).unwrap();
+    writeln!(xkb).unwrap();
+
+    if let Some(&symbols) = layout.iter().enumerate()
+            .find(|&(k, s)| s[0] != '\0' && xkb_keycode(k, board_type).is_none())
+            .map(|(_, s)| s) {
+        return Err(format!("Key '{}' has no XKB keycode on this board type",
+                           key_name(symbols)));
+    }
+    for row in -1..=2 {
+        for col in -1..=10 {
+            let k = grid_key(row, col);
+            if let (Some(keycode), [a, b]) =
+                    (xkb_keycode(k, board_type), layout[k]) {
+                if a != '\0' {
+                    writeln!(xkb, "    key <{}> {{ [ {}, {} ] }};",
+                             keycode, xkb_keysym(a), xkb_keysym(b)).unwrap();
+                }
+            }
+        }
+    }
+    writeln!(xkb, "}};").unwrap();
+
+    Ok(xkb)
+}
+
+// Physical keys of a US keyboard with their XKB keycode, symbols and the
+// names of the key and its shifted symbol in QMK, ZMK and kanata
+struct UsKey {
+    xkb: &'static str,
+    symbols: [char; 2],
+    qmk: [&'static str; 2],
+    zmk: [&'static str; 2],
+    kanata: &'static str,
+}
+
+const fn us_key(xkb: &'static str, symbols: [char; 2],
+                qmk: [&'static str; 2], zmk: [&'static str; 2],
+                kanata: &'static str) -> UsKey {
+    UsKey {xkb, symbols, qmk, zmk, kanata}
+}
+
+const US_KEYS: [UsKey; 49] = [
+    us_key("TLDE", ['`', '~'], ["KC_GRV", "KC_TILD"], ["GRAVE", "TILDE"], "grv"),
+    us_key("AE01", ['1', '!'], ["KC_1", "KC_EXLM"], ["N1", "EXCL"], "1"),
+    us_key("AE02", ['2', '@'], ["KC_2", "KC_AT"], ["N2", "AT"], "2"),
+    us_key("AE03", ['3', '#'], ["KC_3", "KC_HASH"], ["N3", "HASH"], "3"),
+    us_key("AE04", ['4', '$'], ["KC_4", "KC_DLR"], ["N4", "DLLR"], "4"),
+    us_key("AE05", ['5', '%'], ["KC_5", "KC_PERC"], ["N5", "PRCNT"], "5"),
+    us_key("AE06", ['6', '^'], ["KC_6", "KC_CIRC"], ["N6", "CARET"], "6"),
+    us_key("AE07", ['7', '&'], ["KC_7", "KC_AMPR"], ["N7", "AMPS"], "7"),
+    us_key("AE08", ['8', '*'], ["KC_8", "KC_ASTR"], ["N8", "STAR"], "8"),
+    us_key("AE09", ['9', '('], ["KC_9", "KC_LPRN"], ["N9", "LPAR"], "9"),
+    us_key("AE10", ['0', ')'], ["KC_0", "KC_RPRN"], ["N0", "RPAR"], "0"),
+    us_key("AE11", ['-', '_'], ["KC_MINS", "KC_UNDS"], ["MINUS", "UNDER"], "-"),
+    us_key("AE12", ['=', '+'], ["KC_EQL", "KC_PLUS"], ["EQUAL", "PLUS"], "="),
+    us_key("AD01", ['q', 'Q'], ["KC_Q", "S(KC_Q)"], ["Q", "LS(Q)"], "q"),
+    us_key("AD02", ['w', 'W'], ["KC_W", "S(KC_W)"], ["W", "LS(W)"], "w"),
+    us_key("AD03", ['e', 'E'], ["KC_E", "S(KC_E)"], ["E", "LS(E)"], "e"),
+    us_key("AD04", ['r', 'R'], ["KC_R", "S(KC_R)"], ["R", "LS(R)"], "r"),
+    us_key("AD05", ['t', 'T'], ["KC_T", "S(KC_T)"], ["T", "LS(T)"], "t"),
+    us_key("AD06", ['y', 'Y'], ["KC_Y", "S(KC_Y)"], ["Y", "LS(Y)"], "y"),
+    us_key("AD07", ['u', 'U'], ["KC_U", "S(KC_U)"], ["U", "LS(U)"], "u"),
+    us_key("AD08", ['i', 'I'], ["KC_I", "S(KC_I)"], ["I", "LS(I)"], "i"),
+    us_key("AD09", ['o', 'O'], ["KC_O", "S(KC_O)"], ["O", "LS(O)"], "o"),
+    us_key("AD10", ['p', 'P'], ["KC_P", "S(KC_P)"], ["P", "LS(P)"], "p"),
+    us_key("AD11", ['[', '{'], ["KC_LBRC", "KC_LCBR"], ["LBKT", "LBRC"], "["),
+    us_key("AD12", [']', '}'], ["KC_RBRC", "KC_RCBR"], ["RBKT", "RBRC"], "]"),
+    us_key("BKSL", ['\\', '|'], ["KC_BSLS", "KC_PIPE"], ["BSLH", "PIPE"], "\\"),
+    us_key("AC01", ['a', 'A'], ["KC_A", "S(KC_A)"], ["A", "LS(A)"], "a"),
+    us_key("AC02", ['s', 'S'], ["KC_S", "S(KC_S)"], ["S", "LS(S)"], "s"),
+    us_key("AC03", ['d', 'D'], ["KC_D", "S(KC_D)"], ["D", "LS(D)"], "d"),
+    us_key("AC04", ['f', 'F'], ["KC_F", "S(KC_F)"], ["F", "LS(F)"], "f"),
+    us_key("AC05", ['g', 'G'], ["KC_G", "S(KC_G)"], ["G", "LS(G)"], "g"),
+    us_key("AC06", ['h', 'H'], ["KC_H", "S(KC_H)"], ["H", "LS(H)"], "h"),
+    us_key("AC07", ['j', 'J'], ["KC_J", "S(KC_J)"], ["J", "LS(J)"], "j"),
+    us_key("AC08", ['k', 'K'], ["KC_K", "S(KC_K)"], ["K", "LS(K)"], "k"),
+    us_key("AC09", ['l', 'L'], ["KC_L", "S(KC_L)"], ["L", "LS(L)"], "l"),
+    us_key("AC10", [';', ':'], ["KC_SCLN", "KC_COLN"], ["SEMI", "COLON"], ";"),
+    us_key("AC11", ['\'', '"'], ["KC_QUOT", "KC_DQUO"], ["SQT", "DQT"], "'"),
+    us_key("LSGT", ['\0', '\0'], ["KC_NUBS", "S(KC_NUBS)"], ["NUBS", "LS(NUBS)"], "102d"),
+    us_key("AB01", ['z', 'Z'], ["KC_Z", "S(KC_Z)"], ["Z", "LS(Z)"], "z"),
+    us_key("AB02", ['x', 'X'], ["KC_X", "S(KC_X)"], ["X", "LS(X)"], "x"),
+    us_key("AB03", ['c', 'C'], ["KC_C", "S(KC_C)"], ["C", "LS(C)"], "c"),
+    us_key("AB04", ['v', 'V'], ["KC_V", "S(KC_V)"], ["V", "LS(V)"], "v"),
+    us_key("AB05", ['b', 'B'], ["KC_B", "S(KC_B)"], ["B", "LS(B)"], "b"),
+    us_key("AB06", ['n', 'N'], ["KC_N", "S(KC_N)"], ["N", "LS(N)"], "n"),
+    us_key("AB07", ['m', 'M'], ["KC_M", "S(KC_M)"], ["M", "LS(M)"], "m"),
+    us_key("AB08", [',', '<'], ["KC_COMM", "KC_LABK"], ["COMMA", "LT"], ","),
+    us_key("AB09", ['.', '>'], ["KC_DOT", "KC_RABK"], ["DOT", "GT"], "."),
+    us_key("AB10", ['/', '?'], ["KC_SLSH", "KC_QUES"], ["FSLH", "QMARK"], "/"),
+    us_key("SPCE", [' ', '\0'], ["KC_SPC", "S(KC_SPC)"], ["SPACE", "LS(SPACE)"], "spc"),
+];
+
+// US key and shift level that type a symbol
+fn us_symbol(c: char, format: &str) -> Result<(&'static UsKey, usize), String> {
+    US_KEYS.iter()
+           .find_map(|key| key.symbols.iter().position(|&s| s == c)
+                                      .map(|level| (key, level)))
+           .filter(|_| c != '\0')
+           .ok_or_else(|| format!("Symbol '{}' can't be typed with {} keycodes",
+                                  c, format))
+}
+
+// Keys with a shifted symbol other than the one on the same US key need
+// an explicit override. The override is keyed by the name of the normal
+// symbol.
+fn shift_override([a, b]: [char; 2], format: &str)
+    -> Result<Option<String>, String>
+{
+    let (key, level) = us_symbol(a, format)?;
+    if b == '\0' || (level == 0 && key.symbols[1] == b) {
+        Ok(None)
+    } else {
+        us_symbol(b, format)?;
+        Ok(Some(xkb_keysym(a)))
+    }
+}
+
+// Keys of a programmable keyboard in the order of its keymap: the key rows
+// from top to bottom, followed by the thumb keys from left to right. Outer
+// columns and the number row are included if the layout uses them. Space
+// (None) sits between the left and right thumb keys.
+fn board_keys(layout: &Layout) -> Vec<Vec<Option<usize>>> {
+    let exists = |k: &usize| layout[*k][0] != '\0';
+    let first_row = if (-1..=10).map(|c| grid_key(-1, c)).any(|k| exists(&k))
+                    {-1} else {0};
+    let first_col = if (first_row..3).map(|r| grid_key(r, -1)).any(|k| exists(&k))
+                    {-1} else {0};
+    let last_col = if (first_row..3).map(|r| grid_key(r, 10)).any(|k| exists(&k))
+                   {10} else {9};
+    let mut rows: Vec<Vec<_>> = (first_row..3).map(|row|
+        (first_col..=last_col).map(|col| Some(grid_key(row, col))).collect()
+    ).collect();
+
+    // Left thumb keys are numbered from the center outwards
+    let mut thumb_row: Vec<_> = (LTHUMB..RTHUMB).rev().filter(exists)
+                                                .map(Some).collect();
+    thumb_row.push(None);
+    thumb_row.extend((RTHUMB..NUMBER_ROW).filter(exists).map(Some));
+    rows.push(thumb_row);
+    rows
+}
+
+// Writes the rows of a keymap with the keys of the longest rows aligned in
+// columns. Every key but the last one is followed by the delimiter.
+fn write_rows(out: &mut String, indent: &str, rows: &[Vec<String>],
+              delimiter: &str) {
+    let num_keys = rows.iter().map(Vec::len).sum::<usize>();
+    let mut keys = (0..num_keys).map(|i| if i + 1 < num_keys {delimiter} else {""});
+    let rows: Vec<Vec<_>> = rows.iter().map(|row| row.iter().map(|key|
+        format!("{}{}", key, keys.next().unwrap())
+    ).collect()).collect();
+
+    // Shorter rows, such as the thumb keys, don't affect the alignment
+    let max_len = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let mut widths = vec![0; max_len];
+    for row in rows.iter().filter(|row| row.len() == max_len) {
+        for (width, key) in widths.iter_mut().zip(row) {
+            *width = key.chars().count().max(*width);
+        }
+    }
+    for row in rows {
+        let line: Vec<_> = row.iter().zip(&widths)
+                              .map(|(key, &width)| format!("{:1$}", key, width))
+                              .collect();
+        writeln!(out, "{}{}", indent, line.join(" ").trim_end()).unwrap();
+    }
+}
+
+// QMK keymap.c with a single layer. Shifted symbols that differ from the
+// US layout are implemented with key overrides.
+pub fn layout_to_qmk(layout: &Layout, name: &str) -> Result<String, String> {
+    let mut overrides = Vec::new();
+    let mut rows = Vec::new();
+    for keys in board_keys(layout) {
+        let mut row = Vec::new();
+        for key in keys {
+            let symbols = key.map_or([' ', '\0'], |k| layout[k]);
+            if symbols[0] == '\0' {
+                row.push("KC_NO".to_string());
+                continue;
+            }
+            let (us_key, level) = us_symbol(symbols[0], "QMK")?;
+            let keycode = us_key.qmk[level];
+            if let Some(label) = shift_override(symbols, "QMK")? {
+                let (us_key, level) = us_symbol(symbols[1], "QMK")?;
+                overrides.push((format!("ko_{}", label), keycode,
+                                us_key.qmk[level]));
+            }
+            row.push(keycode.to_string());
+        }
+        rows.push(row);
+    }
+
+    let mut qmk = String::new();
+    writeln!(qmk, "// {} keymap generated by Kühlmak", name).unwrap();
+    writeln!(qmk, "#include QMK_KEYBOARD_H").unwrap();
+    writeln!(qmk).unwrap();
+    if !overrides.is_empty() {
+        writeln!(qmk, "// Shifted symbols that differ from the US layout. Requires").unwrap();
+        writeln!(qmk, "// KEY_OVERRIDE_ENABLE = yes in rules.mk").unwrap();
+        for (label, trigger, replacement) in &overrides {
+            writeln!(qmk, "const key_override_t {} = ko_make_basic(MOD_MASK_SHIFT, {}, {});",
+                     label, trigger, replacement).unwrap();
+        }
+        writeln!(qmk).unwrap();
+        writeln!(qmk, "const key_override_t *key_overrides[] = {{").unwrap();
+        for (label, _, _) in &overrides {
+            writeln!(qmk, "    &{},", label).unwrap();
+        }
+        writeln!(qmk, "}};").unwrap();
+        writeln!(qmk).unwrap();
+    }
+    writeln!(qmk, "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {{").unwrap();
+    writeln!(qmk, "    [0] = LAYOUT(").unwrap();
+    write_rows(&mut qmk, "        ", &rows, ",");
+    writeln!(qmk, "    )").unwrap();
+    writeln!(qmk, "}};").unwrap();
+
+    Ok(qmk)
+}
+
+// ZMK .keymap with a single layer. Shifted symbols that differ from the
+// US layout are implemented with mod-morph behaviors.
+pub fn layout_to_zmk(layout: &Layout, name: &str) -> Result<String, String> {
+    let mut behaviors = String::new();
+    let mut rows = Vec::new();
+    for keys in board_keys(layout) {
+        let mut row = Vec::new();
+        for key in keys {
+            let symbols = key.map_or([' ', '\0'], |k| layout[k]);
+            if symbols[0] == '\0' {
+                row.push("&none".to_string());
+                continue;
+            }
+            let (us_key, level) = us_symbol(symbols[0], "ZMK")?;
+            let binding = format!("&kp {}", us_key.zmk[level]);
+            if let Some(label) = shift_override(symbols, "ZMK")? {
+                let (us_key, level) = us_symbol(symbols[1], "ZMK")?;
+                let label = format!("mm_{}", label);
+                writeln!(behaviors, "        {0}: {0} {{", label).unwrap();
+                writeln!(behaviors, "            compatible = \"zmk,behavior-mod-morph\";").unwrap();
+                writeln!(behaviors, "            #binding-cells = <0>;").unwrap();
+                writeln!(behaviors, "            bindings = <{}>, <&kp {}>;",
+                         binding, us_key.zmk[level]).unwrap();
+                writeln!(behaviors, "            mods = <(MOD_LSFT|MOD_RSFT)>;").unwrap();
+                writeln!(behaviors, "        }};").unwrap();
+                row.push(format!("&{}", label));
+            } else {
+                row.push(binding);
+            }
+        }
+        rows.push(row);
+    }
+
+    let mut zmk = String::new();
+    writeln!(zmk, "// {} keymap generated by Kühlmak", name).unwrap();
+    writeln!(zmk, "#include <behaviors.dtsi>").unwrap();
+    writeln!(zmk, "#include <dt-bindings/zmk/keys.h>").unwrap();
+    writeln!(zmk).unwrap();
+    writeln!(zmk, "/ {{").unwrap();
+    if !behaviors.is_empty() {
+        writeln!(zmk, "    // Shifted symbols that differ from the US layout").unwrap();
+        writeln!(zmk, "    behaviors {{").unwrap();
+        zmk.push_str(&behaviors);
+        writeln!(zmk, "    }};").unwrap();
+        writeln!(zmk).unwrap();
+    }
+    writeln!(zmk, "    keymap {{").unwrap();
+    writeln!(zmk, "        compatible = \"zmk,keymap\";").unwrap();
+    writeln!(zmk).unwrap();
+    writeln!(zmk, "        default_layer {{").unwrap();
+    writeln!(zmk, "            display-name = \"{}\";", name.replace('"', "'")).unwrap();
+    writeln!(zmk, "            bindings = <").unwrap();
+    write_rows(&mut zmk, "                ", &rows, "");
+    writeln!(zmk, "            >;").unwrap();
+    writeln!(zmk, "        }};").unwrap();
+    writeln!(zmk, "    }};").unwrap();
+    writeln!(zmk, "}};").unwrap();
+
+    Ok(zmk)
+}
+
+// kanata configuration that remaps the keys of a regular keyboard like
+// the XKB exporter does. Shifted symbols that differ from the US layout
+// are implemented with fork actions.
+pub fn layout_to_kanata(layout: &Layout, board_type: KeyboardType, name: &str)
+    -> Result<String, String>
+{
+    let action = |c: char| -> Result<String, String> {
+        let (us_key, level) = us_symbol(c, "kanata")?;
+        Ok(if level == 0 {us_key.kanata.to_string()}
+           else {format!("S-{}", us_key.kanata)})
+    };
+
+    // Regular keyboards don't have thumb keys other than Space
+    if let Some(&symbols) = layout.iter().enumerate()
+            .find(|&(k, s)| s[0] != '\0' && is_thumb_key(k))
+            .map(|(_, s)| s) {
+        return Err(format!("Key '{}' has no kanata key name on this board type",
+                           key_name(symbols)));
+    }
+
+    let mut src_rows = Vec::new();
+    let mut layer_rows = Vec::new();
+    for row in -1..=2 {
+        let mut src_row = Vec::new();
+        let mut layer_row = Vec::new();
+        for col in -1..=10 {
+            let k = grid_key(row, col);
+            let symbols = layout[k];
+            if symbols[0] == '\0' {
+                continue;
+            }
+            let us_key = xkb_keycode(k, board_type)
+                .and_then(|code| US_KEYS.iter().find(|key| key.xkb == code))
+                .ok_or_else(|| format!(
+                    "Key '{}' has no kanata key name on this board type",
+                    key_name(symbols)))?;
+            src_row.push(us_key.kanata.to_string());
+            layer_row.push(if shift_override(symbols, "kanata")?.is_some() {
+                // Shift is still held when the shifted action runs
+                let (_, level) = us_symbol(symbols[1], "kanata")?;
+                let shifted = action(symbols[1])?;
+                format!("(fork {} {} (lsft rsft))", action(symbols[0])?,
+                        if level == 0 {format!("(unmod {})", shifted)}
+                        else {shifted})
+            } else {
+                action(symbols[0])?
+            });
+        }
+        if !src_row.is_empty() {
+            src_rows.push(src_row);
+            layer_rows.push(layer_row);
+        }
+    }
+
+    let mut kanata = String::new();
+    writeln!(kanata, ";; {} layout generated by Kühlmak", name).unwrap();
+    writeln!(kanata, "(defsrc").unwrap();
+    write_rows(&mut kanata, "  ", &src_rows, "");
+    writeln!(kanata, ")").unwrap();
+    writeln!(kanata).unwrap();
+    writeln!(kanata, "(deflayer {}", name.split_whitespace().collect::<Vec<_>>()
+                                          .join("-")).unwrap();
+    write_rows(&mut kanata, "  ", &layer_rows, "");
+    writeln!(kanata, ")").unwrap();
+
+    Ok(kanata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::layout_from_str;
+
+    static LAYOUT: &str =
+"  q  w  f  p  b  j  l  u  y -_
+  a  r  s  t  g  m  n  e  i  o
+  z  x  c  d  v  k  h ,; .: /?
+";
+
+    // The left bottom row moves one key to the left on ISO boards
+    #[test]
+    fn xkb_iso() {
+        let layout = layout_from_str(LAYOUT).unwrap();
+        let ansi = layout_to_xkb(&layout, KeyboardType::ANSI, "test").unwrap();
+        let iso = layout_to_xkb(&layout, KeyboardType::ISO, "test").unwrap();
+        assert!(ansi.contains("key <AB01> { [ z, Z ] };"));
+        assert!(ansi.contains("key <AB05> { [ v, V ] };"));
+        assert!(iso.contains("key <LSGT> { [ z, Z ] };"));
+        assert!(iso.contains("key <AB04> { [ v, V ] };"));
+        assert!(!iso.contains("<AB05>"));
+        assert!(iso.contains("key <AB08> { [ comma, semicolon ] };"));
+    }
+
+    // Only shifted symbols that differ from the US layout need overrides
+    #[test]
+    fn shift_overrides() {
+        let layout = layout_from_str(LAYOUT).unwrap();
+        let qmk = layout_to_qmk(&layout, "test").unwrap();
+        assert!(qmk.contains("ko_make_basic(MOD_MASK_SHIFT, KC_COMM, KC_SCLN)"));
+        assert!(qmk.contains("ko_make_basic(MOD_MASK_SHIFT, KC_DOT, KC_COLN)"));
+        assert_eq!(qmk.matches("ko_make_basic").count(), 2);
+        let zmk = layout_to_zmk(&layout, "test").unwrap();
+        assert!(zmk.contains("bindings = <&kp COMMA>, <&kp SEMI>;"));
+        assert_eq!(zmk.matches("mod-morph").count(), 2);
+        let kanata = layout_to_kanata(&layout, KeyboardType::Ortho, "test")
+                     .unwrap();
+        assert!(kanata.contains("(fork , (unmod ;) (lsft rsft))"));
+        assert!(kanata.contains("(fork . S-; (lsft rsft))"));
+
+        let layout = layout_from_str(&LAYOUT.replace("-_", "-ä")).unwrap();
+        assert!(layout_to_qmk(&layout, "test").is_err());
+    }
+}
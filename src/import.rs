@@ -0,0 +1,378 @@
+use super::{Layout, KeyboardType, layout_from_str};
+use super::eval::{NUM_KEYS, grid_key};
+use super::export::{xkb_keycode, key_name, ASCII_KEYSYMS, LATIN1_KEYSYMS};
+use serde_json::Value;
+
+// Layout and the keys that were dropped from it
+pub type ImportedLayout = (Layout, Vec<String>);
+
+// Imported layouts only use the 3x10 grid of core keys, not the number row,
+// outer columns or thumb keys. Keys outside the core keys are dropped. That
+// is an error for letters, which would be missing from the layout. Other
+// dropped keys are returned so they can be reported.
+fn check_outside(symbols: [char; 2], location: &str)
+    -> Result<Option<String>, String>
+{
+    if symbols.iter().any(|c| c.is_alphabetic()) {
+        Err(format!("Key '{}' at {} is outside the core keys that can be imported",
+                    key_name(symbols), location))
+    } else if symbols == ['\0'; 2] {
+        Ok(None)
+    } else {
+        Ok(Some(format!("key '{}' at {}", key_name(symbols), location)))
+    }
+}
+
+fn check_layout(layout: &Layout, location: impl Fn(usize) -> String)
+    -> Result<(), String>
+{
+    if let Some(key) = (0..30).find(|&k| layout[k][0] == '\0') {
+        return Err(format!("Missing key at {}", location(key)));
+    }
+    let mut symbols: Vec<char> = layout.iter().flatten().copied()
+                                       .filter(|&c| c != '\0').collect();
+    symbols.sort_unstable();
+    if let Some(pair) = symbols.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(format!("Duplicated symbol in layout: '{}'", pair[0]));
+    }
+    Ok(())
+}
+
+// Keys in the layout with the given XKB keycodes. Symbols that can't be
+// parsed are only an error on core keys.
+fn layout_from_keycodes(keys: Vec<(String, Result<[char; 2], String>)>,
+                        board_type: KeyboardType)
+    -> Result<ImportedLayout, String>
+{
+    let mut layout: Layout = [['\0'; 2]; NUM_KEYS];
+    let mut ignored = Vec::new();
+    for (keycode, symbols) in keys {
+        let location = format!("<{}>", keycode);
+        let key = (0..30).find(|&k| xkb_keycode(k, board_type).as_ref()
+                                    == Some(&keycode));
+        match (key, symbols) {
+            (Some(k), Ok(symbols)) => layout[k] = symbols,
+            (Some(_), Err(e)) => return Err(format!("{} at {}", e, location)),
+            (None, Ok(symbols)) =>
+                ignored.extend(check_outside(symbols, &location)?),
+            (None, Err(_)) => (),
+        }
+    }
+    check_layout(&layout, |k| format!("<{}>",
+                                     xkb_keycode(k, board_type).unwrap()))?;
+    Ok((layout, ignored))
+}
+
+fn keysym_char(keysym: &str) -> Result<char, String> {
+    let mut chars = keysym.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(c);
+    }
+    if keysym == "NoSymbol" || keysym == "VoidSymbol" {
+        return Ok('\0');
+    }
+    if let Some(&(c, _)) = ASCII_KEYSYMS.iter().find(|&&(_, s)| s == keysym) {
+        return Ok(c);
+    }
+    if let Some(i) = LATIN1_KEYSYMS.iter().position(|&s| s == keysym) {
+        return Ok(char::from_u32(0xa0 + i as u32).unwrap());
+    }
+    // Unicode keysyms are written as U+hex or as 0x01000000 + code point
+    keysym.strip_prefix('U').and_then(|hex| u32::from_str_radix(hex, 16).ok())
+          .or_else(|| keysym.strip_prefix("0x")
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            .filter(|&code| code > 0x01000000)
+                            .map(|code| code - 0x01000000))
+          .and_then(char::from_u32)
+          .ok_or_else(|| format!("Unsupported keysym '{}'", keysym))
+}
+
+// Contents between a '{' at the start of text and the matching '}'
+fn braced(text: &str) -> Option<&str> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => return Some(&text[1..i]),
+            '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+// First symbols section of an XKB symbols file. Keys that the section gets
+// from included sections are not known.
+pub fn layout_from_xkb(text: &str, board_type: KeyboardType)
+    -> Result<ImportedLayout, String>
+{
+    let text: String = text.lines()
+                           .map(|line| line.split("//").next().unwrap())
+                           .collect::<Vec<_>>().join("\n");
+    let section = text.find("xkb_symbols")
+                      .and_then(|i| text[i..].find('{').map(|j| i + j))
+                      .and_then(|i| braced(&text[i..]))
+                      .ok_or("No xkb_symbols section found")?;
+
+    let mut keys = Vec::new();
+    let mut rest = section;
+    while let Some(i) = rest.find("key <") {
+        rest = &rest[i + 5..];
+        let end = rest.find('>').ok_or("Unterminated key name")?;
+        let keycode = rest[..end].to_string();
+        let body = rest.find('{').and_then(|i| braced(&rest[i..]))
+                       .ok_or_else(|| format!("Invalid definition of key <{}>",
+                                              keycode))?;
+        // Skip group names such as symbols[Group1] and take the first list
+        // of keysyms
+        let levels = body.split('[').skip(1)
+                         .filter_map(|s| s.split(']').next())
+                         .find(|s| !s.trim().starts_with("Group"))
+                         .ok_or_else(|| format!("No symbols for key <{}>",
+                                                keycode))?;
+        let mut symbols = Ok(['\0'; 2]);
+        for (level, keysym) in levels.split(',').take(2).enumerate() {
+            symbols = symbols.and_then(|mut symbols| {
+                symbols[level] = keysym_char(keysym.trim())?;
+                Ok(symbols)
+            });
+        }
+        keys.push((keycode, symbols));
+    }
+    layout_from_keycodes(keys, board_type)
+}
+
+// XKB keycode of a Windows scan code
+fn scan_code_keycode(scan_code: u32) -> Option<String> {
+    Some(match scan_code {
+        0x02..=0x0d => format!("AE{:02}", scan_code - 0x01),
+        0x10..=0x1b => format!("AD{:02}", scan_code - 0x0f),
+        0x1e..=0x28 => format!("AC{:02}", scan_code - 0x1d),
+        0x2c..=0x35 => format!("AB{:02}", scan_code - 0x2b),
+        0x29 => "TLDE".to_string(),
+        0x2b => "BKSL".to_string(),
+        0x56 => "LSGT".to_string(),
+        _ => return None,
+    })
+}
+
+// Symbols in a KLC file are code points in hex or literal characters.
+// Dead keys have a trailing '@', -1 means no symbol.
+fn klc_char(symbol: &str) -> Result<char, String> {
+    let symbol = symbol.strip_suffix('@').unwrap_or(symbol);
+    let mut chars = symbol.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ if symbol == "-1" => Ok('\0'),
+        _ => u32::from_str_radix(symbol, 16).ok().and_then(char::from_u32)
+                .ok_or_else(|| format!("Unsupported symbol '{}'", symbol)),
+    }
+}
+
+// LAYOUT section of a Windows keyboard layout (.klc) file from Microsoft
+// Keyboard Layout Creator
+pub fn layout_from_klc(text: &str, board_type: KeyboardType)
+    -> Result<ImportedLayout, String>
+{
+    let mut lines = text.lines()
+                        .map(|line| line.split("//").next().unwrap().trim())
+                        .skip_while(|line| !line.starts_with("LAYOUT"));
+    if lines.next().is_none() {
+        return Err("No LAYOUT section found".to_string());
+    }
+
+    let mut keys = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        // The LAYOUT section ends with the next keyword
+        let scan_code = match u32::from_str_radix(fields[0], 16) {
+            Ok(scan_code) if fields.len() >= 5 => scan_code,
+            _ => break,
+        };
+        if let Some(keycode) = scan_code_keycode(scan_code) {
+            let symbols = klc_char(fields[3]).and_then(|a|
+                klc_char(fields[4]).map(|b| [a, b]));
+            keys.push((keycode, symbols));
+        }
+    }
+    layout_from_keycodes(keys, board_type)
+}
+
+// Symbols of a key from its legends in keyboard-layout-editor.com. A
+// letter on its own gets both cases. Other keys show the shifted symbol
+// above the normal one. Keys with longer legends, such as Shift, are not
+// part of the layout.
+fn kle_symbols(label: &str) -> Option<[char; 2]> {
+    let label = label.replace("&lt;", "<").replace("&gt;", ">")
+                     .replace("&quot;", "\"").replace("&#39;", "'")
+                     .replace("&amp;", "&");
+    let legends: Vec<_> = label.split('\n').collect();
+    let single = |s: &str| {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+    if legends[2.min(legends.len())..].iter().any(|s| !s.is_empty()) {
+        return None;
+    }
+    match legends.get(1).filter(|s| !s.is_empty()) {
+        None => {
+            let c = single(legends[0])?;
+            if c.is_alphabetic() && c.to_lowercase().count() == 1
+                                 && c.to_uppercase().count() == 1 {
+                Some([c.to_lowercase().next().unwrap(),
+                      c.to_uppercase().next().unwrap()])
+            } else {
+                Some([c, '\0'])
+            }
+        },
+        Some(normal) => Some([single(normal)?, single(legends[0])?]),
+    }
+}
+
+// Raw data of a keyboard-layout-editor.com layout, as downloaded in JSON
+// format. The last three rows with symbol keys are the 3x10 grid. Each row
+// of the grid starts with the first symbol key that is not left of the
+// first symbol key of the top row. Further symbol keys in the row and
+// other rows are outside the grid.
+pub fn layout_from_kle(text: &str) -> Result<ImportedLayout, String> {
+    let json: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let rows = json.as_array().ok_or("Expected a list of rows")?;
+
+    // Symbol keys with their position, as well as their row and key number
+    // for error messages
+    let mut key_rows: Vec<Vec<(f64, String, [char; 2])>> = Vec::new();
+    for (r, row) in rows.iter().filter_map(Value::as_array).enumerate() {
+        let (mut x, mut width) = (0.0, 1.0);
+        let mut keys = Vec::new();
+        let mut n = 0;
+        for item in row {
+            match item {
+                Value::Object(props) => {
+                    x += props.get("x").and_then(Value::as_f64).unwrap_or(0.0);
+                    width = props.get("w").and_then(Value::as_f64)
+                                 .unwrap_or(width);
+                },
+                Value::String(label) => {
+                    n += 1;
+                    if let Some(symbols) = kle_symbols(label) {
+                        let location = format!("row {}, key {}", r + 1, n);
+                        keys.push((x, location, symbols));
+                    }
+                    x += width;
+                    width = 1.0;
+                },
+                _ => return Err("Expected key properties or a legend"
+                                .to_string()),
+            }
+        }
+        if !keys.is_empty() {
+            key_rows.push(keys);
+        }
+    }
+    if key_rows.len() < 3 {
+        return Err(format!("Found only {} rows with symbol keys. Expected 3 or more",
+                           key_rows.len()));
+    }
+
+    let mut layout: Layout = [['\0'; 2]; NUM_KEYS];
+    let mut ignored = Vec::new();
+    let first_row = key_rows.len() - 3;
+    let left_edge = key_rows[first_row][0].0 - 0.2;
+    for (r, keys) in key_rows.into_iter().enumerate() {
+        let first_col = if r < first_row {keys.len()}
+                        else {keys.iter().take_while(|&(x, _, _)| *x < left_edge)
+                                  .count()};
+        for (c, (_, location, symbols)) in keys.into_iter().enumerate() {
+            if c >= first_col && c < first_col + 10 {
+                layout[grid_key((r - first_row) as i8,
+                                (c - first_col) as i8)] = symbols;
+            } else {
+                ignored.extend(check_outside(symbols, &location)?);
+            }
+        }
+    }
+    check_layout(&layout, |k| format!("row {}, column {} of the grid",
+                                     k / 10 + 1, k % 10 + 1))?;
+    Ok((layout, ignored))
+}
+
+// Layout from a file in any of the supported formats, recognized by its
+// contents. Everything else is parsed as a Kühlmak layout, which may also
+// start with '[' if that is the symbol on the first key.
+pub fn import_layout(text: &str, board_type: KeyboardType)
+    -> Result<ImportedLayout, String>
+{
+    let text = text.trim_start_matches('\u{feff}');
+    if serde_json::from_str::<Vec<Value>>(text).is_ok() {
+        layout_from_kle(text)
+    } else if text.contains("xkb_symbols") {
+        layout_from_xkb(text, board_type)
+    } else if text.lines().any(|line| line.starts_with("KBD")) &&
+              text.lines().any(|line| line.starts_with("LAYOUT")) {
+        layout_from_klc(text, board_type)
+    } else {
+        layout_from_str(text).map(|layout| (layout, Vec::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::layout_to_xkb;
+
+    static LAYOUT: &str =
+"  q  w  f  p  b  j  l  u  y -_
+  a  r  s  t  g  m  n  e  i  o
+  z  x  c  d  v  k  h ,; .: /ä
+";
+
+    // Exported XKB symbols import to the same layout on every board type
+    #[test]
+    fn xkb_round_trip() {
+        let layout = layout_from_str(LAYOUT).unwrap();
+        for board_type in [KeyboardType::Ortho, KeyboardType::ANSI,
+                           KeyboardType::ISO] {
+            let xkb = layout_to_xkb(&layout, board_type, "test").unwrap();
+            let (imported, ignored) = layout_from_xkb(&xkb, board_type).unwrap();
+            assert!(imported == layout);
+            assert!(ignored.is_empty());
+        }
+    }
+
+    // Symbol keys left of the first top row key and right of the 10th key
+    // in each row are outside the grid
+    #[test]
+    fn kle_grid() {
+        let kle = r##"[{"name": "test"},
+            ["!\n1", "@\n2", "#\n3", "$\n4", "%\n5", "^\n6", "&\n7", "*\n8", "(\n9", ")\n0"],
+            [{"w": 1.5}, "Tab", "Q", "W", "F", "P", "B", "J", "L", "U", "Y", "_\n-", "+\n="],
+            [{"w": 1.75}, "Caps", "A", "R", "S", "T", "G", "M", "N", "E", "I", "O"],
+            [{"w": 1.25}, "Shift", "|\n\\", "Z", "X", "C", "D", "V", "K", "H", ";\n,", ":\n.", "ä\n/"]
+        ]"##;
+        let (layout, ignored) = layout_from_kle(kle).unwrap();
+        assert!(layout == layout_from_str(LAYOUT).unwrap());
+        // Every dropped symbol key is reported, but not Tab, Caps or Shift
+        assert_eq!(ignored.len(), 12);
+        assert_eq!(ignored[0], "key '1!' at row 1, key 1");
+        assert_eq!(ignored[10], "key '=+' at row 2, key 12");
+        assert_eq!(ignored[11], "key '\\|' at row 4, key 2");
+
+        let kle = kle.replace(r#""+\n=""#, r#""Ü""#);
+        assert_eq!(layout_from_kle(&kle).unwrap_err(),
+                   "Key 'Ü' at row 2, key 12 is outside the core keys that can be imported");
+    }
+
+    // Only JSON goes to the KLE importer. A layout file may start with '['.
+    #[test]
+    fn kbl_with_brackets() {
+        let kbl = "[{ w  f  p  b  j  l  u  y ]}\n\
+                   a  r  s  t  g  m  n  e  i  o\n\
+                   z  x  c  d  v  k  h ,; .: /?\n";
+        let (layout, ignored) = import_layout(kbl, KeyboardType::Ortho).unwrap();
+        assert!(layout == layout_from_str(kbl).unwrap());
+        assert!(ignored.is_empty());
+    }
+}
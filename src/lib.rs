@@ -1,11 +1,22 @@
 mod text_stats;
 mod eval;
 mod anneal;
+mod export;
+mod import;
+mod report;
+mod diff;
 
-pub use text_stats::{TextStats, Symbol, Bigram, Trigram};
+pub use text_stats::{TextStats, Symbol, Bigram, Trigram, SHIFT};
 pub use eval::{
     Layout, KeyboardType, EvalModel, EvalScores,
     layout_from_str, layout_to_str, layout_to_filename, serde_layout,
-    KuehlmakModel, KuehlmakParams, KuehlmakScores
+    swap_columns, KuehlmakModel, KuehlmakParams, KuehlmakScores
 };
-pub use anneal::{Anneal};
+pub use anneal::{Anneal, AnnealState};
+pub use export::{layout_to_xkb, layout_to_qmk, layout_to_zmk, layout_to_kanata};
+pub use import::{layout_from_xkb, layout_from_klc, layout_from_kle, import_layout,
+                 ImportedLayout};
+pub use report::{
+    scores_to_html, scores_to_svg, KeyColors, scores_to_json, JSON_VERSION
+};
+pub use diff::{swap_sequence, write_diff};
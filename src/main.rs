@@ -1,24 +1,30 @@
-use kuehlmak::TextStats;
+use kuehlmak::{TextStats, SHIFT};
 use kuehlmak::{
-    layout_from_str, layout_to_str, serde_layout, Layout,
+    layout_from_str, layout_to_str, layout_to_filename, serde_layout, Layout,
     EvalModel, EvalScores,
     KuehlmakModel, KuehlmakParams, KuehlmakScores,
-    Anneal
+    Anneal, AnnealState,
+    layout_to_xkb, layout_to_qmk, layout_to_zmk, layout_to_kanata,
+    import_layout, KeyboardType, scores_to_html, scores_to_svg, KeyColors,
+    scores_to_json, JSON_VERSION, write_diff, swap_columns
 };
 
 use clap::{clap_app, ArgMatches};
 
 use serde::{Serialize, Deserialize};
+use serde_json::json;
+use toml_edit::{Item, TableLike};
 
 use threadpool;
 use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use std::path::{PathBuf, Path};
-use std::str::FromStr;
 use std::ffi::OsStr;
+use std::collections::BTreeMap;
 use std::process;
 use std::env;
-use std::io::{Read, Write, self};
+use std::io::{Read, Write, IsTerminal, self};
 use std::fs;
 
 static QWERTY: &str =
@@ -47,9 +53,53 @@ fn layout_from_file<P>(path: P) -> (Layout, usize)
     }), popularity)
 }
 
+// Colors are only used in terminals, unless disabled with NO_COLOR
+fn use_color() -> bool {
+    io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+// Layout files from other tools are recognized by their contents. Windows
+// keyboard layout files are usually encoded in UTF-16.
+fn import_layout_from_file(path: &str, board_type: KeyboardType) -> Layout {
+    let bytes = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read layout file '{}': {}", path, e);
+        process::exit(1)
+    });
+    let string = match bytes.as_slice() {
+        [0xff, 0xfe, rest @ ..] => String::from_utf16_lossy(
+            &rest.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]))
+                 .collect::<Vec<_>>()),
+        _ => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+    let (layout, ignored) = import_layout(&string, board_type)
+                                .unwrap_or_else(|e| {
+        eprintln!("Failed to parse layout '{}': {}", path, e);
+        process::exit(1)
+    });
+    for key in ignored {
+        eprintln!("Ignoring {} outside the core keys in '{}'", key, path);
+    }
+    layout
+}
+
+// A single corpus file or a weighted mix of several
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Corpus {
+    File(PathBuf),
+    Mix(Vec<CorpusWeight>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CorpusWeight {
+    path: PathBuf,
+    weight: f64,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Config {
-    corpus: PathBuf,
+    corpus: Corpus,
     #[serde(with = "serde_layout", default)]
     initial_layout: Option<Layout>,
     #[serde(flatten)]
@@ -64,28 +114,39 @@ fn config_from_file<P>(path: P) -> Config
                   path.as_ref().display(), e);
         process::exit(1)
     });
+    config_from_str(&c, path.as_ref())
+}
 
+// Parse configuration text as if it was read from the file at path
+fn config_from_str(c: &str, path: &Path) -> Config {
     // Change current directory to make relative paths in the config behave
     let prev_dir = env::current_dir().expect("Failed to get current dir");
-    if let Some(dir) = path.as_ref().parent() {
+    if let Some(dir) = path.parent() {
         if dir != Path::new("") {
             env::set_current_dir(dir).expect("Failed to set current dir");
         }
     }
-    let mut config: Config = toml::from_str(&c).unwrap_or_else(|e| {
-        eprintln!("Failed to parse config file '{}': {}",
-                  path.as_ref().display(), e);
+    let mut config: Config = toml::from_str(c).unwrap_or_else(|e| {
+        eprintln!("Failed to parse config file '{}': {}", path.display(), e);
         process::exit(1)
     });
-    config.corpus = config.corpus.canonicalize().unwrap_or_else(|e| {
-        eprintln!("Invalid path '{}': {}", config.corpus.display(), e);
-        process::exit(1);
-    });
+    let canonicalize = |path: &mut PathBuf| {
+        *path = path.canonicalize().unwrap_or_else(|e| {
+            eprintln!("Invalid path '{}': {}", path.display(), e);
+            process::exit(1);
+        })
+    };
+    match &mut config.corpus {
+        Corpus::File(path) => canonicalize(path),
+        Corpus::Mix(mix) => mix.iter_mut().for_each(|c| canonicalize(&mut c.path)),
+    }
     env::set_current_dir(&prev_dir).expect("Failed to set current dir");
     config
 }
 
-fn text_from_file(path: Option<&Path>) -> TextStats {
+// With shift == true, Shift symbols are inserted before capitalized letters
+// when reading plain text. JSON corpora are used as they are.
+fn text_from_file(path: Option<&Path>, shift: bool) -> TextStats {
     let mut is_json = false;
     let contents = if let Some(path) = path {
         is_json = path.extension().map(|e| e.to_ascii_lowercase() == "json")
@@ -111,9 +172,65 @@ fn text_from_file(path: Option<&Path>) -> TextStats {
         })
     } else {
         // This shouldn't panic
-        TextStats::from_str(&contents).unwrap()
+        TextStats::from_text(&contents, shift).unwrap()
+    }
+}
+
+// Plain text corpora get Shift symbols if the configuration has a Shift key
+fn text_from_corpus(config: &Config) -> TextStats {
+    let shift = config.params.has_shift_key();
+    match &config.corpus {
+        Corpus::File(path) => text_from_file(Some(path.as_path()), shift),
+        Corpus::Mix(mix) => mix_corpora(
+            mix.iter().map(|c| (c.path.as_path(), c.weight)), shift),
+    }
+}
+
+fn mix_corpora<'a, I>(corpora: I, shift: bool) -> TextStats
+    where I: IntoIterator<Item = (&'a Path, f64)>
+{
+    let texts: Vec<_> = corpora.into_iter().map(|(path, weight)| {
+        if !(weight > 0.0 && weight.is_finite()) {
+            eprintln!("Invalid weight {} for corpus '{}'", weight,
+                      path.display());
+            process::exit(1);
+        }
+        (text_from_file(Some(path), shift), weight)
+    }).collect();
+    if texts.is_empty() {
+        eprintln!("No corpus to mix");
+        process::exit(1);
+    }
+    TextStats::mix(texts.iter().map(|(text, weight)| (text, *weight)))
+}
+
+// Annealing run that is yet to be started or resumed from a checkpoint
+#[derive(Serialize, Deserialize)]
+enum AnnealJob {
+    New {seed: u64, steps: u64, shuffle: bool},
+    Resume {seed: u64, state: AnnealState},
+}
+
+enum AnnealMsg {
+    Output(Vec<u8>),
+    Interrupted(AnnealJob),
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Let the first Ctrl-C stop annealing gracefully. A second one terminates
+// the program immediately.
+#[cfg(unix)]
+fn catch_interrupt() {
+    extern "C" fn handler(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        unsafe {libc::signal(libc::SIGINT, libc::SIG_DFL);}
     }
+    let handler: extern "C" fn(libc::c_int) = handler;
+    unsafe {libc::signal(libc::SIGINT, handler as libc::sighandler_t);}
 }
+#[cfg(not(unix))]
+fn catch_interrupt() {}
 
 fn anneal_command(sub_m: &ArgMatches) {
     let dir: &Path = sub_m.value_of("dir").unwrap_or(".").as_ref();
@@ -121,6 +238,16 @@ fn anneal_command(sub_m: &ArgMatches) {
         eprintln!("Not a directory: '{}'", dir.display());
         process::exit(1);
     }
+    // A new batch that gets interrupted would overwrite the unfinished runs
+    // of an earlier one
+    let checkpoint: PathBuf =
+        [dir, "checkpoint.json".as_ref()].into_iter().collect();
+    let resume = sub_m.is_present("resume");
+    if !resume && checkpoint.exists() {
+        eprintln!("Found unfinished runs in '{}'. Continue them with --resume or delete the file.",
+                  checkpoint.display());
+        process::exit(1);
+    }
     let db_config: PathBuf = [dir,"config.toml".as_ref()].into_iter().collect();
     let config = sub_m.value_of("config").map(Path::new)
                       .or(Some(db_config.as_path()).filter(|p| p.is_file()))
@@ -134,9 +261,10 @@ fn anneal_command(sub_m: &ArgMatches) {
         None => layout_from_str(QWERTY).unwrap(),
     };
 
-    let text = text_from_file(Some(config.corpus.as_path()));
+    let text = text_from_corpus(&config);
     let mut alphabet: Vec<_> = layout.iter().flatten().copied().collect();
     alphabet.push(' ');
+    alphabet.push(SHIFT);
     alphabet.sort();
     let text = text.filter(|c| alphabet.binary_search(&c).is_ok(), 1);
 
@@ -153,6 +281,7 @@ fn anneal_command(sub_m: &ArgMatches) {
     };
     let progress = sub_m.is_present("progress");
     let show_scores = sub_m.is_present("show_scores");
+    let color = use_color();
 
     let jobs: Option<usize> = sub_m.value_of("jobs").map(|number| {
         number.parse().unwrap_or_else(|e| {
@@ -167,6 +296,32 @@ fn anneal_command(sub_m: &ArgMatches) {
         }),
         None => 1,
     };
+    // Job i uses seed + i, so each run can be reproduced individually
+    let seed: u64 = match sub_m.value_of("seed") {
+        Some(seed) => seed.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid seed '{}': {}", seed, e);
+            process::exit(1)
+        }),
+        None => rand::random(),
+    };
+
+    let anneal_jobs: Vec<AnnealJob> = if resume {
+        let file = fs::File::open(&checkpoint).unwrap_or_else(|e| {
+            eprintln!("Failed to open checkpoint '{}': {}",
+                      checkpoint.display(), e);
+            process::exit(1)
+        });
+        serde_json::from_reader(io::BufReader::new(file)).unwrap_or_else(|e| {
+            eprintln!("Failed to parse checkpoint '{}': {}",
+                      checkpoint.display(), e);
+            process::exit(1)
+        })
+    } else {
+        (0..n).map(|i| AnnealJob::New {seed: seed.wrapping_add(i as u64),
+                                       steps, shuffle})
+              .collect()
+    };
+    catch_interrupt();
 
     // Generate n layouts using j (or number-of-CPU) worker threads
     let builder = threadpool::Builder::new();
@@ -174,7 +329,14 @@ fn anneal_command(sub_m: &ArgMatches) {
                                              .build();
     let (tx, rx) = channel();
     let stdout = &mut io::stdout();
-    for _ in 0..n {
+    let mut interrupted = Vec::new();
+    let mut anneal_jobs = anneal_jobs.into_iter();
+    for job in anneal_jobs.by_ref() {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            interrupted.push(job);
+            break;
+        }
+
         // Clone stuff that gets moved into the worker closure
         let model = kuehlmak_model.clone();
         let text = text.clone();
@@ -182,17 +344,36 @@ fn anneal_command(sub_m: &ArgMatches) {
         let dir = dir.to_owned();
 
         pool.execute(move || {
-            let mut anneal = Anneal::new(&model, &text, layout, shuffle, steps);
-            let mut scores = model.eval_layout(&layout, &text, 1.0, false);
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                tx.send(AnnealMsg::Interrupted(job)).unwrap();
+                return;
+            }
+            let (seed, mut anneal) = match &job {
+                &AnnealJob::New {seed, steps, shuffle} => (seed,
+                    Anneal::new(&model, &text, layout, shuffle, steps, seed)),
+                AnnealJob::Resume {seed, state} => (*seed,
+                    Anneal::resume(&model, &text, state).unwrap_or_else(|e| {
+                        eprintln!("Invalid layout in checkpoint: {}", e);
+                        process::exit(1)
+                    })),
+            };
+            let mut scores = anneal.scores();
 
             while let Some(s) = anneal.next() {
+                if INTERRUPTED.load(Ordering::Relaxed) {
+                    let state = anneal.checkpoint();
+                    tx.send(AnnealMsg::Interrupted(AnnealJob::Resume {seed, state}))
+                      .unwrap();
+                    return;
+                }
                 if progress {
                     let mut w = Vec::new();
                     anneal.write_stats(&mut w).unwrap();
-                    s.write(&mut w, show_scores).unwrap();
-                    // VT100: cursor up 9 rows
-                    write!(&mut w, "\x1b[9A").unwrap();
-                    tx.send(w).unwrap();
+                    s.write(&mut w, show_scores, color).unwrap();
+                    // VT100: cursor up to the first row
+                    let rows = w.iter().filter(|&&c| c == b'\n').count();
+                    write!(&mut w, "\x1b[{}A", rows).unwrap();
+                    tx.send(AnnealMsg::Output(w)).unwrap();
                 }
 
                 scores = s;
@@ -201,10 +382,10 @@ fn anneal_command(sub_m: &ArgMatches) {
             let mut w = Vec::new();
             let scores = model.eval_layout(&scores.layout(), &text, 1.0, true);
             writeln!(&mut w).unwrap();
-            scores.write(&mut w, show_scores).unwrap();
-            tx.send(w).unwrap();
+            scores.write(&mut w, show_scores, color).unwrap();
+            tx.send(AnnealMsg::Output(w)).unwrap();
 
-            scores.write_to_db(&dir, show_scores).unwrap();
+            scores.write_to_db(&dir, show_scores, Some(seed)).unwrap();
         });
 
         // Process messages until the queue drops below a threshold. This
@@ -212,9 +393,14 @@ fn anneal_command(sub_m: &ArgMatches) {
         // Assume that workers send messages before terminating, so we can
         // wait for messages without worrying that workers will go idle.
         while pool.queued_count() >= pool.max_count() {
-            stdout.write(&rx.recv().unwrap()).unwrap();
+            match rx.recv().unwrap() {
+                AnnealMsg::Output(w) => {stdout.write(&w).unwrap();},
+                AnnealMsg::Interrupted(job) => interrupted.push(job),
+            }
         }
     }
+    // Jobs that were never submitted because of an interruption
+    interrupted.extend(anneal_jobs);
 
     // Drop the original sender so the receiver will start failing once all
     // the Senders in the workers have hung up.
@@ -223,7 +409,28 @@ fn anneal_command(sub_m: &ArgMatches) {
     // Drain any remaining messages. This implicitly waits for the workers
     // to finish.
     while let Ok(msg) = rx.recv() {
-        stdout.write(&msg).unwrap();
+        match msg {
+            AnnealMsg::Output(w) => {stdout.write(&w).unwrap();},
+            AnnealMsg::Interrupted(job) => interrupted.push(job),
+        }
+    }
+
+    if !interrupted.is_empty() {
+        let json = serde_json::to_string(&interrupted).unwrap();
+        fs::write(&checkpoint, json).unwrap_or_else(|e| {
+            eprintln!("Failed to write checkpoint '{}': {}",
+                      checkpoint.display(), e);
+            process::exit(1)
+        });
+        eprintln!("\nInterrupted. Continue {} unfinished runs with --resume.",
+                  interrupted.len());
+        // Like the shell's exit status after SIGINT
+        process::exit(130);
+    } else if resume {
+        fs::remove_file(&checkpoint).unwrap_or_else(|e| {
+            eprintln!("Failed to remove checkpoint '{}': {}",
+                      checkpoint.display(), e);
+        });
     }
 }
 
@@ -235,29 +442,223 @@ fn eval_command(sub_m: &ArgMatches) {
         process::exit(1);
     });
 
-    let text = text_from_file(Some(config.corpus.as_path()));
+    let text = text_from_corpus(&config);
     // Not filtering with any alphabet because different layouts may use
     // different alphabets.
 
     let verbose = sub_m.is_present("verbose");
     let show_scores = sub_m.is_present("show_scores");
+    let color = use_color();
 
+    let board_type = config.params.board_type();
     let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    if sub_m.is_present("interactive") {
+        let mut filenames = sub_m.values_of("LAYOUT").into_iter().flatten();
+        let (Some(filename), None) = (filenames.next(), filenames.next()) else {
+            eprintln!("Interactive mode takes exactly one layout");
+            process::exit(1)
+        };
+        let layout = import_layout_from_file(filename, board_type);
+        eval_interactive(&kuehlmak_model, &text, layout, color);
+        return;
+    }
+
+    let html = sub_m.value_of("html");
+    let mut html_layouts = Vec::new();
+    let json = sub_m.is_present("json");
+    let mut json_layouts = Vec::new();
     let stdout = &mut io::stdout();
 
     for filename in sub_m.values_of("LAYOUT").into_iter().flatten() {
-        let (layout, _) = layout_from_file(filename);
+        let layout = import_layout_from_file(filename, board_type);
+
+        let scores = kuehlmak_model.eval_layout(&layout, &text, 1.0,
+                                                verbose || html.is_some());
+
+        if json {
+            let mut layout_json = scores_to_json(&scores);
+            if !verbose {
+                // Only computed for the HTML report
+                layout_json.as_object_mut().unwrap().remove("ngrams");
+            }
+            layout_json["file"] = json!(filename);
+            json_layouts.push(layout_json);
+        } else {
+            println!("=== {} ===================", filename);
+            scores.write(stdout, show_scores, color).unwrap();
+            if verbose {
+                scores.write_extra(stdout, color).unwrap();
+            }
+        }
+        if html.is_some() {
+            html_layouts.push((filename.to_string(), scores));
+        }
+    }
+
+    if json {
+        print_json(json!({
+            "version": JSON_VERSION,
+            "command": "eval",
+            "layouts": json_layouts,
+        }));
+    }
+    if let Some(path) = html {
+        write_html(path, "Layout evaluation", &html_layouts);
+    }
+}
+
+fn print_json(json: serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+}
+
+const INTERACTIVE_HELP: &str = "\
+Commands:
+  swap <a> <b>       Swap the keys with symbols a and b
+  swap-col <a> <b>   Swap columns a and b (1-10 from the left pinky, 0 and 11
+                     for the outer columns)
+  undo               Undo the last change
+  baseline           Compare scores with the current layout from now on
+  save <file>        Save the layout to a file
+  show [extra]       Print the evaluation, optionally with n-gram lists
+  help               Print this help
+  quit               Leave interactive mode";
+
+// Scores that changed since the previous and the baseline layout. The total
+// is always shown.
+fn print_score_deltas(scores: &[f64], prev: &[f64], baseline: &[f64]) {
+    let mut names: Vec<_> = KuehlmakScores::get_score_names().into_iter()
+                                                          .collect();
+    names.sort_by_key(|&(_, i)| i);
+    println!("       Score      Value    Change  Baseline");
+    for (name, i) in names {
+        let (change, since_base) = (scores[i] - prev[i],
+                                    scores[i] - baseline[i]);
+        if i == 0 || change.abs() >= 0.005 || since_base.abs() >= 0.005 {
+            println!("{:>12}  {:9.2} {:+9.2} {:+9.2}", name, scores[i], change,
+                     since_base);
+        }
+    }
+}
+
+// Interactive mode of eval for tweaking a layout by hand. The layout is
+// re-evaluated after every change.
+fn eval_interactive(model: &KuehlmakModel, text: &TextStats,
+                    mut layout: Layout, color: bool) {
+    let stdout = &mut io::stdout();
+    let eval = |layout: &Layout, extra| model.eval_layout(layout, text, 1.0,
+                                                          extra);
+    let first = eval(&layout, false);
+    first.write(stdout, false, color).unwrap();
+    let mut scores = first.get_scores();
+    let mut baseline = scores.clone();
+    let mut history: Vec<Layout> = Vec::new();
+    println!("Type 'help' for a list of commands.");
+
+    let find_key = |layout: &Layout, symbol: &str| {
+        let mut chars = symbol.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => layout.iter().position(|k| k.contains(&c))
+                                     .ok_or(format!("No key with symbol '{}'", c)),
+            _ => Err(format!("Expected a single symbol, got '{}'", symbol)),
+        }
+    };
+    let parse_col = |col: &str| match col.parse::<i8>() {
+        Ok(col) if (0..=11).contains(&col) => Ok(col - 1),
+        _ => Err(format!("Expected a column from 0 to 11, got '{}'", col)),
+    };
 
-        let scores = kuehlmak_model.eval_layout(&layout, &text, 1.0, verbose);
+    loop {
+        print!("> ");
+        stdout.flush().unwrap();
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                // End the prompt line
+                println!();
+                break;
+            },
+            Ok(_) => (),
+        }
 
-        println!("=== {} ===================", filename);
-        scores.write(stdout, show_scores).unwrap();
-        if verbose {
-            scores.write_extra(stdout).unwrap();
+        let words: Vec<_> = line.split_whitespace().collect();
+        let new_layout = match words.as_slice() {
+            [] => continue,
+            ["help"] => {
+                println!("{}", INTERACTIVE_HELP);
+                continue;
+            },
+            ["quit"] | ["exit"] => break,
+            ["swap", sa, sb] => find_key(&layout, sa).and_then(|a|
+                find_key(&layout, sb).and_then(|b| {
+                    if a == b {
+                        return Err(format!("'{}' and '{}' are on the same key",
+                                           sa, sb));
+                    }
+                    let mut l = layout;
+                    l.swap(a, b);
+                    Ok(l)
+                })),
+            ["swap-col", a, b] => parse_col(a).and_then(|a|
+                parse_col(b).and_then(|b| {
+                    let mut l = layout;
+                    match swap_columns(&mut l, a, b) {
+                        0 => Err(format!("No keys swapped in columns {} and {}",
+                                         a + 1, b + 1)),
+                        _ => Ok(l),
+                    }
+                })),
+            ["undo"] => history.pop().ok_or("Nothing to undo".to_string()),
+            ["baseline"] => {
+                baseline = scores.clone();
+                println!("Comparing scores with the current layout");
+                continue;
+            },
+            ["save", path] => {
+                if let Err(e) = fs::write(path, layout_to_str(&layout)) {
+                    eprintln!("Failed to write '{}': {}", path, e);
+                }
+                continue;
+            },
+            ["show"] => {
+                eval(&layout, false).write(stdout, false, color).unwrap();
+                continue;
+            },
+            ["show", "extra"] => {
+                let s = eval(&layout, true);
+                s.write(stdout, false, color).unwrap();
+                s.write_extra(stdout, color).unwrap();
+                continue;
+            },
+            ["swap", ..] => Err("Usage: swap <a> <b>".to_string()),
+            ["swap-col", ..] => Err("Usage: swap-col <a> <b>".to_string()),
+            ["save", ..] => Err("Usage: save <file>".to_string()),
+            _ => Err(format!("Unknown command '{}'. Type 'help' for a list of commands.",
+                             line.trim())),
+        };
+
+        match new_layout {
+            Ok(new_layout) => {
+                if words[0] != "undo" {
+                    history.push(layout);
+                }
+                layout = new_layout;
+                let new_scores = eval(&layout, false).get_scores();
+                print!("{}", layout_to_str(&layout));
+                print_score_deltas(&new_scores, &scores, &baseline);
+                scores = new_scores;
+            },
+            Err(e) => eprintln!("{}", e),
         }
     }
 }
 
+fn write_html(path: &str, title: &str, layouts: &[(String, KuehlmakScores)]) {
+    if let Err(e) = fs::write(path, scores_to_html(title, layouts)) {
+        eprintln!("Failed to write '{}': {}", path, e);
+        process::exit(1);
+    }
+}
+
 fn get_dir_paths(dir: &str) -> io::Result<Vec<PathBuf>> {
     fs::read_dir(dir)?
         .map(|res| res.map(|e| e.path()))
@@ -284,12 +685,234 @@ fn layouts_from_paths(paths: Vec<PathBuf>) -> Vec<(Layout, usize)> {
     }
 
     if ignored.len() > 0 {
-        println!("Ignoring {}", ignored);
+        eprintln!("Ignoring {}", ignored);
     }
 
     layouts
 }
 
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// CSV columns: layout, popularity, all scores in the order of get_scores,
+// cumulative rank, the rank by each score in score_names and optionally
+// the Pareto layer and crowding distance
+fn print_csv_header(score_names: &str, pareto: bool) {
+    let mut names: Vec<_> = KuehlmakScores::get_score_names().into_iter()
+                                                          .collect();
+    names.sort_by_key(|&(_, i)| i);
+    let mut header = vec!["layout".to_string(), "popularity".to_string()];
+    header.extend(names.into_iter().map(|(name, _)| name));
+    header.push("rank".to_string());
+    header.extend(score_names.split(',').map(|name| format!("rank_{}", name)));
+    if pareto {
+        header.extend(["layer".to_string(), "crowding".to_string()]);
+    }
+    let header: Vec<_> = header.iter().map(|h| csv_field(h)).collect();
+    println!("{}", header.join(","));
+}
+
+fn print_csv_row(layout: &Layout, comp_scores: &[f64], rank: usize,
+                 comp_rank: &[usize], layer: Option<(usize, f64)>,
+                 score_names: &str,
+                 score_name_map: &BTreeMap<String, usize>) {
+    let (popularity, scores) = comp_scores.split_last().unwrap();
+    let mut row = vec![csv_field(&layout_to_str(layout)),
+                       format!("{}", *popularity as usize)];
+    row.extend(scores.iter().map(|s| format!("{}", s)));
+    row.push(format!("{}", rank));
+    for name in score_names.split(',') {
+        let raw_name = name.strip_prefix('+').unwrap_or(name);
+        row.push(format!("{}", comp_rank[score_name_map[raw_name]]));
+    }
+    if let Some((l, crowding)) = layer {
+        row.extend([format!("{}", l), format!("{}", crowding)]);
+    }
+    println!("{}", row.join(","));
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {Lt, Le, Gt, Ge, Eq, Ne}
+
+// Score-based condition for filtering layouts, e.g.
+// "SFBs<8 && scissors<1 || popularity>=2". It is a list of alternatives
+// (||), each a list of comparisons that must all be true (&&).
+struct Filter(Vec<Vec<(usize, Comparison, f64)>>);
+
+impl Filter {
+    fn parse(expr: &str, score_name_map: &BTreeMap<String, usize>)
+        -> Result<Self, String>
+    {
+        // Longer operators first, so that "<=" doesn't match as "<"
+        const OPERATORS: [(&str, Comparison); 6] = [
+            ("<=", Comparison::Le), (">=", Comparison::Ge),
+            ("==", Comparison::Eq), ("!=", Comparison::Ne),
+            ("<", Comparison::Lt), (">", Comparison::Gt),
+        ];
+        let parse_comparison = |cond: &str| {
+            let parsed = cond.find(['<', '>', '=', '!']).and_then(|pos| {
+                let (name, rest) = cond.split_at(pos);
+                let &(op, cmp) = OPERATORS.iter()
+                                          .find(|(op, _)| rest.starts_with(op))?;
+                let value = rest[op.len()..].trim().parse::<f64>().ok()?;
+                Some((name.trim(), cmp, value))
+            }).filter(|(name, _, _)| !name.is_empty());
+            let Some((name, cmp, value)) = parsed else {
+                return Err(format!("Invalid filter condition '{}'. Expected <score><op><number> \
+                                    with <op> one of <, <=, >, >=, ==, !=", cond.trim()));
+            };
+            match score_name_map.get(name) {
+                Some(&score) => Ok((score, cmp, value)),
+                None => Err(unknown_score_message(name, score_name_map)),
+            }
+        };
+        expr.split("||").map(|alt| alt.split("&&").map(parse_comparison)
+                                      .collect())
+            .collect::<Result<_, _>>().map(Filter)
+    }
+
+    fn matches(&self, comp_scores: &[f64]) -> bool {
+        self.0.iter().any(|alt| alt.iter().all(|&(score, cmp, value)| {
+            let s = comp_scores[score];
+            match cmp {
+                Comparison::Lt => s < value,
+                Comparison::Le => s <= value,
+                Comparison::Gt => s > value,
+                Comparison::Ge => s >= value,
+                Comparison::Eq => s == value,
+                Comparison::Ne => s != value,
+            }
+        }))
+    }
+}
+
+fn unknown_score_message(name: &str,
+                         score_name_map: &BTreeMap<String, usize>) -> String {
+    let mut message = format!("Unknown score name {}. Valid names are:", name);
+    for name in score_name_map.keys() {
+        message.push_str("\n  ");
+        message.push_str(name);
+    }
+    message
+}
+
+fn unknown_score_name(name: &str, score_name_map: &BTreeMap<String, usize>)
+    -> ! {
+    eprintln!("{}", unknown_score_message(name, score_name_map));
+    process::exit(1);
+}
+
+// Sort scores by different criteria and add up rankings per layout. The
+// last score is the popularity, which weights the rankings.
+fn rank_layouts<T>(scores: &mut [(T, Vec<f64>, usize, Vec<usize>)],
+                   score_names: &str,
+                   score_name_map: &BTreeMap<String, usize>) {
+    for name in score_names.split(',') {
+        let raw_name = name.strip_prefix('+').unwrap_or(name);
+
+        if let Some(&score) = score_name_map.get(raw_name) {
+            let mut sorted_scores: Vec<_> = scores.iter_mut().collect();
+            sorted_scores.sort_by(|(_, a, _, _), (_, b, _, _)|
+                                  a[score].partial_cmp(&b[score]).unwrap());
+            if name.starts_with('+') {
+                sorted_scores.reverse();
+            }
+            let mut r = 0;
+            let mut inc = *sorted_scores[0].1.last().unwrap() as usize;
+            let mut prev = sorted_scores[0].1[score];
+            for (_, comp_score, rank, comp_rank) in sorted_scores.into_iter()
+                                                                 .skip(1) {
+                // Give the same rank to layouts with equal score
+                if prev != comp_score[score] {
+                    r += inc;
+                    inc = 0;
+                    prev = comp_score[score];
+                }
+                inc += *comp_score.last().unwrap() as usize;
+                comp_rank[score] = r;
+                *rank += r;
+            }
+        } else {
+            unknown_score_name(name, score_name_map);
+        }
+    }
+}
+
+// Pareto layers and crowding distances of layouts by the scores in
+// score_names. Layer 1 is the set of layouts that no other layout beats in
+// every score. Each further layer is non-dominated once the previous layers
+// are removed. Within a layer, a larger crowding distance means that a
+// layout has fewer neighbours with similar trade-offs. Layouts at the ends
+// of the layer get an infinite crowding distance.
+fn pareto_layers<T>(scores: &[(T, Vec<f64>, usize, Vec<usize>)],
+                    score_names: &str,
+                    score_name_map: &BTreeMap<String, usize>)
+    -> Vec<(usize, f64)>
+{
+    // Minimize all objectives, negating scores with a '+' prefix
+    let objectives: Vec<Vec<f64>> = score_names.split(',').map(|name| {
+        let (score, sign) = match name.strip_prefix('+') {
+            Some(raw_name) => (score_name_map[raw_name], -1.0),
+            None => (score_name_map[name], 1.0),
+        };
+        scores.iter().map(|(_, cs, _, _)| cs[score] * sign).collect()
+    }).collect();
+    let dominates = |a: usize, b: usize|
+        objectives.iter().all(|o| o[a] <= o[b]) &&
+        objectives.iter().any(|o| o[a] < o[b]);
+
+    let n = scores.len();
+    let mut dominated_by = vec![0usize; n];
+    let mut dominated: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for (i, dominated) in dominated.iter_mut().enumerate() {
+        for (j, dominated_by) in dominated_by.iter_mut().enumerate() {
+            if dominates(i, j) {
+                dominated.push(j);
+                *dominated_by += 1;
+            }
+        }
+    }
+
+    let mut result = vec![(0usize, 0.0f64); n];
+    let mut layer: Vec<usize> = (0..n).filter(|&i| dominated_by[i] == 0)
+                                      .collect();
+    let mut l = 1;
+    while !layer.is_empty() {
+        for &i in layer.iter() {
+            result[i].0 = l;
+        }
+        for o in objectives.iter() {
+            layer.sort_by(|&a, &b| o[a].total_cmp(&o[b]));
+            let (first, last) = (layer[0], *layer.last().unwrap());
+            result[first].1 = f64::INFINITY;
+            result[last].1 = f64::INFINITY;
+            if o[last] > o[first] {
+                for w in layer.windows(3) {
+                    result[w[1]].1 += (o[w[2]] - o[w[0]]) / (o[last] - o[first]);
+                }
+            }
+        }
+
+        let mut next = Vec::new();
+        for &i in layer.iter() {
+            for &j in dominated[i].iter() {
+                dominated_by[j] -= 1;
+                if dominated_by[j] == 0 {
+                    next.push(j);
+                }
+            }
+        }
+        layer = next;
+        l += 1;
+    }
+    result
+}
+
 fn rank_command(sub_m: &ArgMatches) {
     let dir = sub_m.value_of("dir").unwrap_or(".");
     let db_config: PathBuf = [dir,"config.toml".as_ref()].into_iter().collect();
@@ -309,7 +932,7 @@ fn rank_command(sub_m: &ArgMatches) {
     };
     let layouts = layouts_from_paths(paths);
 
-    let text = text_from_file(Some(config.corpus.as_path()));
+    let text = text_from_corpus(&config);
     // Not filtering with any alphabet because different layouts may use
     // different alphabets.
 
@@ -323,52 +946,54 @@ fn rank_command(sub_m: &ArgMatches) {
         cs.push(*p as f64);
         (s, cs, 0usize, vec![0usize; score_name_map.len()])
     }).collect();
-
-    if scores.len() == 0 {
-        println!("No layouts found.");
-        return;
+    if let Some(expr) = sub_m.value_of("filter") {
+        let filter = Filter::parse(expr, &score_name_map).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1)
+        });
+        scores.retain(|(_, cs, _, _)| filter.matches(cs));
     }
 
-    // Sort scores by different criteria and add up rankings per layout
+    let json = sub_m.is_present("json");
+    let csv = sub_m.is_present("csv");
     let score_names = sub_m.value_of("scores").unwrap_or("total");
-    for name in score_names.split(',') {
-        let raw_name = name.strip_prefix('+').unwrap_or(name);
-
-        if let Some(&score) = score_name_map.get(raw_name) {
-            let mut sorted_scores: Vec<_> = scores.iter_mut().collect();
-            sorted_scores.sort_by(|(_, a, _, _), (_, b, _, _)|
-                                  a[score].partial_cmp(&b[score]).unwrap());
-            if name.starts_with('+') {
-                sorted_scores.reverse();
-            }
-            let mut r = 0;
-            let mut inc = *sorted_scores[0].1.last().unwrap() as usize;
-            let mut prev = sorted_scores[0].1[score];
-            for (_, comp_score, rank, comp_rank) in sorted_scores.into_iter()
-                                                                 .skip(1) {
-                // Give the same rank to layouts with equal score
-                if prev != comp_score[score] {
-                    r += inc;
-                    inc = 0;
-                    prev = comp_score[score];
-                }
-                inc += *comp_score.last().unwrap() as usize;
-                comp_rank[score] = r;
-                *rank += r;
-            }
+    if scores.len() == 0 {
+        if json {
+            print_json(json!({
+                "version": JSON_VERSION,
+                "command": "rank",
+                "layouts": [],
+            }));
+        } else if csv {
+            print_csv_header(score_names, sub_m.is_present("pareto"));
         } else {
-            eprintln!("Unknown score name {}. Valid names are:", name);
-            for name in score_name_map.keys() {
-                eprintln!("  {}", name);
-            }
-            process::exit(1);
+            println!("No layouts found.");
         }
+        return;
     }
-    let show_scores = sub_m.is_present("show_scores");
 
-    // Sort scores by cumulative ranking
-    let mut ranked_scores: Vec<_> = scores.iter().collect();
-    ranked_scores.sort_by_key(|&(_, _, r, _)| r);
+    rank_layouts(&mut scores, score_names, &score_name_map);
+    let show_scores = sub_m.is_present("show_scores");
+    let color = use_color();
+
+    // Sort scores by cumulative ranking, or by Pareto layer and crowding
+    // distance
+    let pareto = sub_m.is_present("pareto");
+    let layers = if pareto {
+        pareto_layers(&scores, score_names, &score_name_map).into_iter()
+                                                            .map(Some).collect()
+    } else {
+        vec![None; scores.len()]
+    };
+    let mut ranked_scores: Vec<_> = scores.iter().zip(layers).collect();
+    if pareto {
+        ranked_scores.sort_by(|(_, a), (_, b)| {
+            let (a, b) = (a.unwrap(), b.unwrap());
+            a.0.cmp(&b.0).then(b.1.total_cmp(&a.1))
+        });
+    } else {
+        ranked_scores.sort_by_key(|&((_, _, r, _), _)| r);
+    }
 
     // Print the first n layouts
     let n: usize = match sub_m.value_of("number") {
@@ -381,18 +1006,54 @@ fn rank_command(sub_m: &ArgMatches) {
     let n_digits = format!("{}", n).len();
     let prefix = sub_m.value_of("prefix");
     let force = sub_m.is_present("force");
+    let html = sub_m.value_of("html");
+    let mut html_layouts = Vec::new();
+    let mut json_layouts = Vec::new();
     let stdout = &mut io::stdout();
-    for (i, (s, cs, _, cr)) in ranked_scores.into_iter().take(n).enumerate() {
-        print!("=== {:.0}x ", cs.last().unwrap());
+    if csv {
+        print_csv_header(score_names, pareto);
+    }
+    for (i, ((s, cs, r, cr), layer)) in ranked_scores.into_iter().take(n)
+                                                     .enumerate() {
+        let mut heading = format!("{:.0}x ", cs.last().unwrap());
+        if let Some((l, crowding)) = layer {
+            heading.push_str(&format!("layer={} crowding={:.3} ", l, crowding));
+        }
+        let mut ranks = serde_json::Map::new();
         for name in score_names.split(',') {
             let raw_name = name.strip_prefix('+').unwrap_or(name);
             if let Some(&score) = score_name_map.get(raw_name) {
-                print!("{}={} ", name, cr[score]);
+                heading.push_str(&format!("{}={} ", name, cr[score]));
+                ranks.insert(name.to_string(), json!(cr[score]));
             }
         }
-        println!("===");
-        s.write(stdout, show_scores).unwrap();
-        println!();
+        if json {
+            let mut layout_json = scores_to_json(s);
+            layout_json["position"] = json!(i+1);
+            layout_json["rank"] = json!(r);
+            layout_json["ranks"] = ranks.into();
+            layout_json["popularity"] = json!(*cs.last().unwrap() as usize);
+            if let Some((l, crowding)) = layer {
+                layout_json["layer"] = json!(l);
+                // Infinite crowding distances become null
+                layout_json["crowding"] = json!(crowding);
+            }
+            json_layouts.push(layout_json);
+        } else if csv {
+            print_csv_row(&s.layout(), cs, *r, cr, layer, score_names,
+                          &score_name_map);
+        } else {
+            println!("=== {}===", heading);
+            s.write(stdout, show_scores, color).unwrap();
+            println!();
+        }
+        if html.is_some() {
+            // Rank scores don't include the n-gram lists
+            let extra = kuehlmak_model.eval_layout(&s.layout(), &text, 1.0,
+                                                   true);
+            html_layouts.push((format!("#{} {}", i+1, heading.trim_end()),
+                               extra));
+        }
         if let Some(p) = prefix {
             let path = format!("{}{:0width$}.kbl", p, i+1, width = n_digits);
             let path = Path::new(&path);
@@ -405,6 +1066,17 @@ fn rank_command(sub_m: &ArgMatches) {
             }
         }
     }
+
+    if json {
+        print_json(json!({
+            "version": JSON_VERSION,
+            "command": "rank",
+            "layouts": json_layouts,
+        }));
+    }
+    if let Some(path) = html {
+        write_html(path, "Layout ranking", &html_layouts);
+    }
 }
 
 fn estimate_population_size(u: usize, k: usize) -> usize {
@@ -434,6 +1106,43 @@ fn estimate_population_size(u: usize, k: usize) -> usize {
     n
 }
 
+// Popularity-weighted histogram of (score, popularity) pairs with equally
+// wide bins from the lowest to the highest score
+fn histogram(values: &[(f64, usize)], bins: usize) -> Vec<(f64, f64, usize)> {
+    let min = values.iter().map(|v| v.0).fold(f64::INFINITY, f64::min);
+    let max = values.iter().map(|v| v.0).fold(f64::NEG_INFINITY, f64::max);
+    let bins = if max > min {bins} else {1};
+    let width = (max - min) / bins as f64;
+    let mut hist: Vec<_> = (0..bins).map(|b| (min + width * b as f64,
+                                              min + width * (b + 1) as f64, 0))
+                                    .collect();
+    for &(v, p) in values {
+        let b = if width > 0.0 {((v - min) / width) as usize} else {0};
+        hist[b.min(bins - 1)].2 += p;
+    }
+    hist
+}
+
+fn print_histogram(name: &str, hist: &[(f64, f64, usize)]) {
+    const WIDTH: usize = 50;
+    const PARTS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+    let max = hist.iter().map(|h| h.2).max().unwrap_or(0).max(1);
+
+    println!("{}:", name);
+    for &(lo, hi, count) in hist {
+        // Bar length in eighths of a character
+        let eighths = count * WIDTH * 8 / max;
+        let (full, part) = (eighths / 8, eighths % 8);
+        let mut bar = "█".repeat(full);
+        if part > 0 {
+            bar.push(PARTS[part]);
+        }
+        println!("{:8.1} -{:8.1} | {:<width$} {}", lo, hi, bar, count,
+                 width = WIDTH);
+    }
+    println!();
+}
+
 fn stats_command(sub_m: &ArgMatches) {
     let dir = sub_m.value_of("dir").unwrap_or(".");
     let db_config: PathBuf = [dir,"config.toml".as_ref()].into_iter().collect();
@@ -453,22 +1162,48 @@ fn stats_command(sub_m: &ArgMatches) {
     };
     let layouts = layouts_from_paths(paths);
 
-    let text = text_from_file(Some(config.corpus.as_path()));
+    let text = text_from_corpus(&config);
     // Not filtering with any alphabet because different layouts may use
     // different alphabets.
 
     let kuehlmak_model = KuehlmakModel::new(Some(config.params));
     let mut score_name_map = KuehlmakScores::get_score_names();
     score_name_map.insert("popularity".to_string(), score_name_map.len());
-    let mut sample_size = 0usize;
 
     let mut scores: Vec<_> = layouts.iter().map(|(l, p)| {
         let s = kuehlmak_model.eval_layout(l, &text, 1.0, false);
         let mut cs = s.get_scores();
         cs.push(*p as f64);
-        sample_size += *p;
         (s, cs)
     }).collect();
+    if let Some(expr) = sub_m.value_of("filter") {
+        let filter = Filter::parse(expr, &score_name_map).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1)
+        });
+        scores.retain(|(_, cs)| filter.matches(cs));
+    }
+    let sample_size: usize = scores.iter()
+                                   .map(|(_, cs)| *cs.last().unwrap() as usize)
+                                   .sum();
+
+    if sub_m.is_present("csv") {
+        // Scores and ranks of all layouts instead of the population stats
+        let score_names = sub_m.value_of("scores").unwrap_or("total");
+        let mut ranked: Vec<_> = scores.iter().map(|(s, cs)|
+            (s, cs.clone(), 0usize, vec![0usize; score_name_map.len()])
+        ).collect();
+        if !ranked.is_empty() {
+            rank_layouts(&mut ranked, score_names, &score_name_map);
+        }
+        ranked.sort_by_key(|&(_, _, r, _)| r);
+        print_csv_header(score_names, false);
+        for (s, cs, r, cr) in ranked.iter() {
+            print_csv_row(&s.layout(), cs, *r, cr, None, score_names,
+                          &score_name_map);
+        }
+        return;
+    }
 
     // To estimate the expected number of unique layouts, a random draw from
     // a finite population of solutions is not a good model because the
@@ -499,70 +1234,262 @@ fn stats_command(sub_m: &ArgMatches) {
                                         if *uni < *pop {*pop} else {*uni + 1});
     }
 
-    println!();
-    println!("Unique/total layouts found: {}/{}, >{} unique layouts expected",
-             scores.len(), sample_size,
-             part_pop[0].1*2 + part_pop[1].2 + part_pop[2].2);
-    println!();
-
-    if scores.len() == 0 {
-        return;
+    let json = sub_m.is_present("json");
+    let expected = part_pop[0].1*2 + part_pop[1].2 + part_pop[2].2;
+    if !json {
+        println!();
+        println!("Unique/total layouts found: {}/{}, >{} unique layouts expected",
+                 scores.len(), sample_size, expected);
+        println!();
     }
+    let mut json_scores = Vec::new();
+    let bins = if sub_m.is_present("histogram") {
+        match sub_m.value_of("bins") {
+            Some(number) => match number.parse() {
+                Ok(bins) if bins > 0 => Some(bins),
+                _ => {
+                    eprintln!("Invalid number of bins '{}'", number);
+                    process::exit(1)
+                }
+            },
+            None => Some(20),
+        }
+    } else {
+        None
+    };
+    let mut histograms = Vec::new();
+
+    if !scores.is_empty() {
+        // Sort scores by different criteria and compute stats
+        if !json {
+            println!("{:>12}: {:^10} {:^10} {:^6} {:^6} {:^6} {:^6} {:^6} {:^6}",
+                     "Score", "Popular", "Min", "Lower", "Median", "Upper", "Max", "IQR", "Range");
+            println!("------------------------------------------------------------------------------");
+        }
+        let score_names = sub_m.value_of("scores").unwrap_or("total");
+        for name in score_names.split(',') {
+            let raw_name = name.strip_prefix('+').unwrap_or(name);
 
-    // Sort scores by different criteria and compute stats
-    println!("{:>12}: {:^10} {:^10} {:^6} {:^6} {:^6} {:^6} {:^6} {:^6}",
-             "Score", "Popular", "Min", "Lower", "Median", "Upper", "Max", "IQR", "Range");
-    println!("------------------------------------------------------------------------------");
-    let score_names = sub_m.value_of("scores").unwrap_or("total");
-    for name in score_names.split(',') {
-        let raw_name = name.strip_prefix('+').unwrap_or(name);
-
-        if let Some(&score) = score_name_map.get(raw_name) {
-            let mut sorted_scores: Vec<_> = scores.iter_mut().collect();
-            sorted_scores.sort_by(|(_, a), (_, b)|
-                                  a[score].partial_cmp(&b[score]).unwrap());
-            if name.starts_with('+') {
-                sorted_scores.reverse();
-            }
-            let mut quartiles = [0f64; 5];
-            quartiles[0] = sorted_scores[0].1[score];
-            let mut c = 0usize;
-            let top_pop = *sorted_scores[0].1.last().unwrap() as usize;
-            let mut max_pop = 0;
-            let mut max_pop_score = 0.0;
-
-            for (_, cs) in sorted_scores {
-                let p = *cs.last().unwrap() as usize;
-                let q0 = c * 4 / sample_size;
-                c += p;
-                let q1 = c * 4 / sample_size;
-                for q in q0..q1 {
-                    quartiles[q+1] = cs[score];
+            if let Some(&score) = score_name_map.get(raw_name) {
+                let mut sorted_scores: Vec<_> = scores.iter_mut().collect();
+                sorted_scores.sort_by(|(_, a), (_, b)|
+                                      a[score].partial_cmp(&b[score]).unwrap());
+                if name.starts_with('+') {
+                    sorted_scores.reverse();
                 }
-                if p > max_pop {
-                    max_pop = p;
-                    max_pop_score = cs[score];
+                let mut quartiles = [0f64; 5];
+                quartiles[0] = sorted_scores[0].1[score];
+                let mut c = 0usize;
+                let top_pop = *sorted_scores[0].1.last().unwrap() as usize;
+                let mut max_pop = 0;
+                let mut max_pop_score = 0.0;
+
+                for (_, cs) in sorted_scores {
+                    let p = *cs.last().unwrap() as usize;
+                    let q0 = c * 4 / sample_size;
+                    c += p;
+                    let q1 = c * 4 / sample_size;
+                    for q in q0..q1 {
+                        quartiles[q+1] = cs[score];
+                    }
+                    if p > max_pop {
+                        max_pop = p;
+                        max_pop_score = cs[score];
+                    }
                 }
+                let iqr = (quartiles[3] - quartiles[1]).abs();
+                let range = (quartiles[4] - quartiles[0]).abs();
+                let hist = bins.map(|bins| {
+                    let values: Vec<_> = scores.iter().map(|(_, cs)|
+                        (cs[score], *cs.last().unwrap() as usize)).collect();
+                    histogram(&values, bins)
+                });
+                if json {
+                    let mut score_json = json!({
+                        "name": name,
+                        "popular": {"score": max_pop_score, "count": max_pop},
+                        "best": {"score": quartiles[0], "count": top_pop},
+                        "quartiles": quartiles,
+                        "iqr": iqr,
+                        "range": range,
+                    });
+                    if let Some(hist) = hist {
+                        score_json["histogram"] = hist.into_iter().map(|(lo, hi, count)|
+                            json!({"min": lo, "max": hi, "count": count})
+                        ).collect();
+                    }
+                    json_scores.push(score_json);
+                } else {
+                    if let Some(hist) = hist {
+                        histograms.push((name, hist));
+                    }
+                    println!("{:>12}: {:6.1}×{:<3} {:6.1}×{:<3} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1}",
+                             name, max_pop_score, max_pop, quartiles[0], top_pop,
+                             quartiles[1], quartiles[2], quartiles[3], quartiles[4],
+                             iqr, range);
+                }
+            } else {
+                unknown_score_name(name, &score_name_map);
             }
-            println!("{:>12}: {:6.1}×{:<3} {:6.1}×{:<3} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1}",
-                     name, max_pop_score, max_pop, quartiles[0], top_pop,
-                     quartiles[1], quartiles[2], quartiles[3], quartiles[4],
-                     (quartiles[3] - quartiles[1]).abs(), (quartiles[4] - quartiles[0]).abs());
-        } else {
-            eprintln!("Unknown score name {}. Valid names are:", name);
-            for name in score_name_map.keys() {
-                eprintln!("  {}", name);
-            }
+        }
+        if !json {
+            println!();
+        }
+        for (name, hist) in histograms {
+            print_histogram(name, &hist);
+        }
+    }
+
+    if json {
+        print_json(json!({
+            "version": JSON_VERSION,
+            "command": "stats",
+            "unique": scores.len(),
+            "total": sample_size,
+            "expected": expected,
+            "scores": json_scores,
+        }));
+    }
+}
+
+fn cluster_command(sub_m: &ArgMatches) {
+    let dir = sub_m.value_of("dir").unwrap_or(".");
+    let db_config: PathBuf = [dir,"config.toml".as_ref()].into_iter().collect();
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(db_config.as_path()).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+    let paths = match get_dir_paths(dir) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Unable to read directory '{}': {}\n{}", dir, e,
+                      sub_m.usage());
             process::exit(1);
         }
+    };
+    let layouts = layouts_from_paths(paths);
+
+    let threshold: f64 = match sub_m.value_of("threshold") {
+        Some(threshold) => threshold.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid threshold '{}': {}", threshold, e);
+            process::exit(1)
+        }),
+        None => 0.1,
+    };
+    let n: usize = match sub_m.value_of("number") {
+        Some(number) => number.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid number '{}': {}", number, e);
+            process::exit(1)
+        }),
+        None => usize::MAX,
+    };
+
+    let text = text_from_corpus(&config);
+    // Not filtering with any alphabet because different layouts may use
+    // different alphabets.
+
+    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let mut score_name_map = KuehlmakScores::get_score_names();
+    score_name_map.insert("popularity".to_string(), score_name_map.len());
+    let name = sub_m.value_of("score").unwrap_or("total");
+    let raw_name = name.strip_prefix('+').unwrap_or(name);
+    let score = *score_name_map.get(raw_name).unwrap_or_else(||
+        unknown_score_name(name, &score_name_map));
+
+    let mut scores: Vec<_> = layouts.iter().map(|(l, p)| {
+        let s = kuehlmak_model.eval_layout(l, &text, 1.0, false);
+        let mut cs = s.get_scores();
+        cs.push(*p as f64);
+        (s.layout(), s, cs)
+    }).collect();
+
+    // Best layouts first, so that they become the representatives of their
+    // clusters
+    scores.sort_by(|(_, _, a), (_, _, b)| a[score].total_cmp(&b[score]));
+    if name.starts_with('+') {
+        scores.reverse();
+    }
+
+    // Each layout joins the first cluster with a representative within the
+    // threshold distance. Clusters are (representative, size, popularity).
+    let distance = |a: &Layout, b: &Layout| match sub_m.value_of("metric") {
+        Some("keys") => kuehlmak_model.moved_symbols(a, b),
+        _ => kuehlmak_model.layout_distance(a, b),
+    };
+    let mut clusters: Vec<(usize, usize, usize)> = Vec::new();
+    for (i, (layout, _, cs)) in scores.iter().enumerate() {
+        let p = *cs.last().unwrap() as usize;
+        match clusters.iter_mut()
+                      .find(|c| distance(&scores[c.0].0, layout) <= threshold) {
+            Some(c) => {
+                c.1 += 1;
+                c.2 += p;
+            },
+            None => clusters.push((i, 1, p)),
+        }
     }
+
+    println!("{} clusters of {} unique layouts", clusters.len(), scores.len());
     println!();
+
+    let show_scores = sub_m.is_present("show_scores");
+    let color = use_color();
+    let out = sub_m.value_of("out").map(Path::new);
+    if let Some(out) = out {
+        if let Err(e) = fs::create_dir_all(out) {
+            eprintln!("Failed to create directory '{}': {}", out.display(), e);
+            process::exit(1);
+        }
+    }
+    let stdout = &mut io::stdout();
+    for &(i, size, popularity) in clusters.iter().take(n) {
+        let (layout, s, cs) = &scores[i];
+        println!("=== {}x layouts={} {}={:.1} ===", popularity, size, name,
+                 cs[score]);
+        s.write(stdout, show_scores, color).unwrap();
+        println!();
+
+        // Save representatives with the combined popularity, so that other
+        // commands like rank can work on distinct solutions
+        if let Some(out) = out {
+            let path = out.join(layout_to_filename(layout));
+            if path.is_file() {
+                eprintln!("Layout file '{}' exists. Not overwriting it.",
+                          path.display());
+                continue;
+            }
+            let result = s.write_to_db(out, false, None).and_then(|_| {
+                let mut file = fs::OpenOptions::new().append(true).open(&path)?;
+                write!(file, "{}", "#".repeat(popularity - 1))
+            });
+            if let Err(e) = result {
+                eprintln!("Failed to write '{}': {}", path.display(), e);
+            }
+        }
+    }
 }
 
 #[allow(clippy::comparison_chain)]
 fn corpus_command(sub_m: &ArgMatches) {
     let text_filename = sub_m.value_of("input").map(|p| p.as_ref());
-    let text = text_from_file(text_filename);
+    let shift = sub_m.is_present("shift");
+    let text = if let Some(mix) = sub_m.value_of("mix") {
+        let corpora: Vec<_> = mix.split(',').map(|c| {
+            let (path, weight) = c.rsplit_once(':').unwrap_or_else(|| {
+                eprintln!("Expected <file>:<weight> in '{}'", c);
+                process::exit(1)
+            });
+            (Path::new(path), weight.parse().unwrap_or_else(|e| {
+                eprintln!("Invalid weight '{}': {}", weight, e);
+                process::exit(1)
+            }))
+        }).collect();
+        mix_corpora(corpora, shift)
+    } else {
+        text_from_file(text_filename, shift)
+    };
     let min: u64 = match sub_m.value_of("min") {
         Some(number) => number.parse().unwrap_or_else(|e| {
             eprintln!("Invalid number '{}': {}", number, e);
@@ -596,6 +1523,9 @@ fn corpus_command(sub_m: &ArgMatches) {
             }
         }
 
+        if shift {
+            alphabet.push(SHIFT);
+        }
         alphabet.sort();
         text.filter(|c| alphabet.binary_search(&c).is_ok(), min)
     } else if min > 1 {
@@ -612,6 +1542,96 @@ fn corpus_command(sub_m: &ArgMatches) {
     println!("{}", j);
 }
 
+fn export_command(sub_m: &ArgMatches) {
+    // The board type matters for some formats, but the defaults are fine
+    // without a configuration file
+    let params = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(|path| config_from_file(path).params)
+                      .unwrap_or_default();
+
+    let filename = sub_m.value_of("LAYOUT").unwrap();
+    let layout = import_layout_from_file(filename, params.board_type());
+    let name = sub_m.value_of("name").map(String::from).unwrap_or_else(|| {
+        Path::new(filename).file_stem().unwrap_or_default()
+                           .to_string_lossy().into_owned()
+    });
+
+    let result = match sub_m.value_of("format").unwrap() {
+        "xkb" => layout_to_xkb(&layout, params.board_type(), &name),
+        "qmk" => layout_to_qmk(&layout, &name),
+        "zmk" => layout_to_zmk(&layout, &name),
+        "kanata" => layout_to_kanata(&layout, params.board_type(), &name),
+        unknown => panic!("Unhandled format: {}", unknown),
+    };
+    match result {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            eprintln!("Failed to export '{}': {}", filename, e);
+            process::exit(1)
+        }
+    }
+}
+
+fn diff_command(sub_m: &ArgMatches) {
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+
+    let text = text_from_corpus(&config);
+    let n: usize = match sub_m.value_of("number") {
+        Some(number) => number.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid number '{}': {}", number, e);
+            process::exit(1)
+        }),
+        None => 20,
+    };
+
+    let board_type = config.params.board_type();
+    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let [a, b] = ["A", "B"].map(|arg| {
+        let layout = import_layout_from_file(sub_m.value_of(arg).unwrap(),
+                                             board_type);
+        kuehlmak_model.eval_layout(&layout, &text, 1.0, true)
+    });
+
+    write_diff(&mut io::stdout(), &a, &b, n).unwrap();
+}
+
+fn svg_command(sub_m: &ArgMatches) {
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+
+    let text = text_from_corpus(&config);
+    let arrows: usize = match sub_m.value_of("arrows") {
+        Some(number) => number.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid number '{}': {}", number, e);
+            process::exit(1)
+        }),
+        None => 0,
+    };
+    let colors = match sub_m.value_of("colors").unwrap_or("heat") {
+        "heat" => KeyColors::Heat,
+        "cost" => KeyColors::Cost,
+        unknown => panic!("Unhandled colors: {}", unknown),
+    };
+
+    let board_type = config.params.board_type();
+    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let filename = sub_m.value_of("LAYOUT").unwrap();
+    let layout = import_layout_from_file(filename, board_type);
+    let scores = kuehlmak_model.eval_layout(&layout, &text, 1.0, arrows > 0);
+
+    print!("{}", scores_to_svg(&scores, colors, arrows));
+}
+
 fn init_command(sub_m: &ArgMatches) {
     // Parse the corpus as a sanity check
     let corpus = sub_m.value_of("corpus").unwrap();
@@ -619,7 +1639,7 @@ fn init_command(sub_m: &ArgMatches) {
         eprintln!("Invalid path '{}': {}", corpus, e);
         process::exit(1);
     });
-    let _corpus = text_from_file(Some(corpus.as_path()));
+    let _corpus = text_from_file(Some(corpus.as_path()), false);
 
     let dir = sub_m.value_of("dir").unwrap_or(".");
     if !Path::new(dir).is_dir() {
@@ -639,7 +1659,7 @@ fn init_command(sub_m: &ArgMatches) {
     }
 
     let config = Config {
-        corpus,
+        corpus: Corpus::File(corpus),
         initial_layout: Some(layout_from_str(QWERTY).unwrap()),
         params: KuehlmakParams::default()
     };
@@ -651,6 +1671,113 @@ fn init_command(sub_m: &ArgMatches) {
     }
 }
 
+// Change a setting in a configuration file, or remove it if value is None.
+// Names of nested settings are separated by dots. Values that aren't valid
+// TOML, such as a bare word like ANSI, are taken as strings. Comments and
+// the formatting of all other settings are kept.
+fn edit_config(doc: &mut toml_edit::Document, name: &str, value: Option<&str>)
+    -> Result<(), String>
+{
+    let error = || if value.is_some() {
+        format!("Invalid setting name '{}'", name)
+    } else {
+        format!("Setting '{}' is not in the configuration", name)
+    };
+    let mut parts: Vec<_> = name.split('.').collect();
+    let key = parts.pop().filter(|k| !k.is_empty()).ok_or_else(error)?;
+    // Comments at the end of the file come after all tables, which would
+    // put them below a new table. Move them in front of it instead.
+    let mut trailing = Some(doc.trailing().as_str().unwrap_or("").to_string());
+    let mut table: &mut dyn TableLike = doc.as_table_mut();
+    for part in parts {
+        if value.is_some() && !table.contains_key(part) {
+            let mut new = toml_edit::Table::new();
+            if let Some(trailing) = trailing.take() {
+                new.decor_mut().set_prefix(format!("{}\n", trailing));
+            }
+            table.insert(part, Item::Table(new));
+        }
+        table = table.get_mut(part).and_then(Item::as_table_like_mut)
+                     .ok_or_else(error)?;
+    }
+    match value {
+        Some(value) => {
+            let mut value = value.parse::<toml_edit::Value>()
+                                 .unwrap_or_else(|_| value.into());
+            // Keep the whitespace and comment around the old value
+            if let Some(old) = table.get(key).and_then(Item::as_value) {
+                *value.decor_mut() = old.decor().clone();
+            }
+            table.insert(key, Item::Value(value));
+        },
+        None => {
+            table.remove(key).ok_or_else(error)?;
+        },
+    }
+    if trailing.is_none() {
+        doc.set_trailing("");
+    }
+    Ok(())
+}
+
+// Configuration as TOML. Going through a Value puts tables and arrays of
+// tables, such as a corpus mix, after all plain settings as TOML requires.
+fn config_to_string(config: &Config) -> Result<String, String> {
+    toml::Value::try_from(config)
+        .and_then(|value| toml::to_string_pretty(&value))
+        .map_err(|e| e.to_string())
+}
+
+fn config_command(sub_m: &ArgMatches) {
+    let config_file = Path::new(sub_m.value_of("config")
+                                     .unwrap_or("config.toml"));
+    let (name, value) = match sub_m.subcommand() {
+        ("set", Some(m)) => {
+            let setting = m.value_of("setting").unwrap();
+            let Some((name, value)) = setting.split_once('=') else {
+                eprintln!("Invalid setting '{}', expected NAME=VALUE", setting);
+                process::exit(1)
+            };
+            (name.trim(), Some(value.trim()))
+        },
+        ("unset", Some(m)) => (m.value_of("setting").unwrap(), None),
+        _ => {
+            let config = config_from_file(config_file);
+            match config_to_string(&config) {
+                Ok(toml) => print!("{}", toml),
+                Err(e) => {
+                    eprintln!("Failed to show configuration: {}", e);
+                    process::exit(1);
+                },
+            }
+            return;
+        },
+    };
+
+    let c = fs::read_to_string(config_file).unwrap_or_else(|e| {
+        eprintln!("Failed to read config file '{}': {}",
+                  config_file.display(), e);
+        process::exit(1)
+    });
+    let mut doc: toml_edit::Document = c.parse().unwrap_or_else(|e| {
+        eprintln!("Failed to parse config file '{}': {}",
+                  config_file.display(), e);
+        process::exit(1)
+    });
+    if let Err(e) = edit_config(&mut doc, name, value) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    // Only write the file if the new configuration is valid
+    let toml = doc.to_string();
+    let _config = config_from_str(&toml, config_file);
+    if let Err(e) = fs::write(config_file, toml) {
+        eprintln!("Failed to write '{}': {}", config_file.display(), e);
+        process::exit(1);
+    }
+}
+
 fn main() {
     let app_m = clap_app!(kuehlmak =>
         (version: "1.0")
@@ -663,10 +1790,14 @@ fn main() {
                 "Filter stats only for those symbols\n(e.g. '-_a-z;,./<>?: ')")
             (@arg min: -m --min +takes_value
                 "Drop symbols and n-grams with lower count")
+            (@arg shift: -s --shift
+                "Insert Shift (⇧) before capitalized letters in text input")
             (@arg pretty: --pretty
                 "Pretty-print JSON output")
-            (@arg input: -i --input +takes_value
+            (@arg input: -i --input +takes_value conflicts_with[mix]
                 "Text or JSON file to use as input [stdin]")
+            (@arg mix: --mix +takes_value
+                "Mix several text or JSON files with weights\n(e.g. 'a.json:0.7,b.json:0.3')")
         )
         (@subcommand anneal =>
             (about: "Generate layouts with Simulated Annealing")
@@ -683,6 +1814,10 @@ fn main() {
                 "Number of layouts to generate [1]")
             (@arg jobs: -j --jobs +takes_value
                 "Number of jobs (threads) to run concurrently [number of CPUs]")
+            (@arg seed: --seed +takes_value conflicts_with[resume]
+                "Random seed of the first job, incremented for each further job [random]")
+            (@arg resume: --resume conflicts_with[number steps noshuffle]
+                "Resume runs from the checkpoint saved in the workspace on Ctrl-C")
             (@arg progress: -p --progress
                 "Print layouts in progress")
             (@arg show_scores: --("show-scores")
@@ -699,6 +1834,13 @@ fn main() {
                 "Layout to evaluate")
             (@arg show_scores: --("show-scores")
                 "Print scores instead of letter and n-gram counts")
+            (@arg html: --html +takes_value
+                "Also write an HTML report to this file")
+            (@arg json: --json
+                "Print results as JSON")
+            (@arg interactive: -i --interactive
+                conflicts_with[html json verbose show_scores]
+                "Edit the layout interactively while watching its scores")
         )
         (@subcommand rank =>
             (about: "Rank layouts")
@@ -717,6 +1859,16 @@ fn main() {
                 "Save ranked layouts to files with this prefix")
             (@arg force: -f --force
                 "Overwrite existing layouts")
+            (@arg html: --html +takes_value
+                "Also write an HTML report of the top-ranked layouts to this file")
+            (@arg json: --json
+                "Print results as JSON")
+            (@arg pareto: --pareto
+                "Rank by Pareto layer and crowding distance instead of cumulative rank")
+            (@arg filter: --filter +takes_value
+                "Only rank layouts matching conditions such as 'SFBs<8 && popularity>=2'")
+            (@arg csv: --csv conflicts_with[json]
+                "Print one CSV row per ranked layout")
         )
         (@subcommand stats =>
             (about: "Print population statistics")
@@ -727,6 +1879,75 @@ fn main() {
                 "Configuration file [<dir>/config.toml]")
             (@arg scores: -s --scores +takes_value
                 "Comma-separated list of scores to show stats for")
+            (@arg json: --json
+                "Print results as JSON")
+            (@arg histogram: --histogram
+                "Show popularity-weighted histograms of the scores")
+            (@arg bins: -b --bins +takes_value requires[histogram]
+                "Number of histogram bins [20]")
+            (@arg filter: --filter +takes_value
+                "Only include layouts matching conditions such as 'SFBs<8 && popularity>=2'")
+            (@arg csv: --csv conflicts_with[json]
+                "Print one CSV row per layout with scores and ranks")
+        )
+        (@subcommand cluster =>
+            (about: "Group similar layouts")
+            (version: "1.0")
+            (@arg dir: -d --dir +takes_value
+                "Workspace directory [current directory]")
+            (@arg config: -c --config +takes_value
+                "Configuration file [<dir>/config.toml]")
+            (@arg threshold: -t --threshold +takes_value
+                "Maximum distance of layouts from their cluster's representative [0.1]")
+            (@arg metric: -m --metric +takes_value
+                possible_value[distance keys]
+                "Layout distance or fraction of symbols on different keys [distance]")
+            (@arg score: -s --score +takes_value
+                "Score that picks the best layout of each cluster [total]")
+            (@arg number: -n --number +takes_value
+                "Number of clusters to output")
+            (@arg show_scores: --("show-scores")
+                "Print scores instead of letter and n-gram counts")
+            (@arg out: -o --out +takes_value
+                "Save representatives with their cluster's popularity to this directory")
+        )
+        (@subcommand export =>
+            (about: "Export a layout for use with other tools")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [./config.toml]")
+            (@arg format: -f --format +takes_value +required
+                possible_value[xkb qmk zmk kanata]
+                "Output format")
+            (@arg name: -n --name +takes_value
+                "Name of the exported layout [layout file name]")
+            (@arg LAYOUT: +required
+                "Layout to export")
+        )
+        (@subcommand diff =>
+            (about: "Compare two layouts")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [./config.toml]")
+            (@arg number: -n --number +takes_value
+                "Number of n-grams that changed category to list [20]")
+            (@arg A: +required
+                "Layout to compare")
+            (@arg B: +required
+                "Layout to compare with")
+        )
+        (@subcommand svg =>
+            (about: "Draw a layout as an SVG image")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [./config.toml]")
+            (@arg colors: --colors +takes_value
+                possible_value[heat cost]
+                "Color keys by usage or effort cost [heat]")
+            (@arg arrows: -a --arrows +takes_value
+                "Number of the most frequent SFBs and scissors to draw as arrows [0]")
+            (@arg LAYOUT: +required
+                "Layout to draw")
         )
         (@subcommand init =>
             (about: "Create workspace and initialize configuration file")
@@ -740,6 +1961,20 @@ fn main() {
             (@arg force: -f --force
                 "Overwrite existing configuration file")
         )
+        (@subcommand config =>
+            (about: "Show or change the configuration")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [config.toml]")
+            (@subcommand set =>
+                (about: "Change a setting, e.g. weights.SFBs=12")
+                (@arg setting: +required "NAME=VALUE")
+            )
+            (@subcommand unset =>
+                (about: "Remove a setting to use its default, e.g. targets.travel")
+                (@arg setting: +required "NAME")
+            )
+        )
     ).get_matches();
 
     match app_m.subcommand_name() {
@@ -751,10 +1986,20 @@ fn main() {
                                               .unwrap()),
         Some("stats") => stats_command(app_m.subcommand_matches("stats")
                                               .unwrap()),
+        Some("cluster") => cluster_command(app_m.subcommand_matches("cluster")
+                                                  .unwrap()),
         Some("corpus") => corpus_command(app_m.subcommand_matches("corpus")
                                                     .unwrap()),
+        Some("diff") => diff_command(app_m.subcommand_matches("diff")
+                                               .unwrap()),
+        Some("svg") => svg_command(app_m.subcommand_matches("svg")
+                                              .unwrap()),
+        Some("export") => export_command(app_m.subcommand_matches("export")
+                                                    .unwrap()),
         Some("init") => init_command(app_m.subcommand_matches("init")
                                                     .unwrap()),
+        Some("config") => config_command(app_m.subcommand_matches("config")
+                                                        .unwrap()),
         Some(unknown) => panic!("Unhandled subcommand: {}", unknown),
         None => {
             eprintln!("No subcommand given.\n{}", app_m.usage());
@@ -762,3 +2007,176 @@ fn main() {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static EFFORT_CONFIG: &str = include_str!("../layouts/effort.toml");
+
+    fn score_name_map() -> BTreeMap<String, usize> {
+        ["SFBs", "scissors", "popularity"].into_iter().enumerate()
+                                          .map(|(i, name)| (name.to_string(), i))
+                                          .collect()
+    }
+
+    fn filter(expr: &str) -> Result<Filter, String> {
+        Filter::parse(expr, &score_name_map())
+    }
+
+    // Minimize SFBs and maximize scissors. A, B and C trade one against the
+    // other. E and F are tied and only beaten by A and B. G is beaten by all.
+    #[test]
+    fn pareto() {
+        let scores: Vec<_> = [("A", 1.0, 5.0), ("B", 2.0, 6.0), ("C", 3.0, 7.0),
+                              ("E", 3.0, 5.0), ("F", 3.0, 5.0), ("G", 4.0, 4.0)]
+            .into_iter().map(|(name, sfbs, scissors)|
+                (name, vec![sfbs, scissors, 1.0], 0, Vec::new()))
+            .collect();
+        let layers = pareto_layers(&scores, "SFBs,+scissors", &score_name_map());
+        // Only B has neighbours on both sides, 1 unit apart in each score,
+        // which is half the range of the layer
+        assert_eq!(layers, [(1, f64::INFINITY), (1, 2.0), (1, f64::INFINITY),
+                            (2, f64::INFINITY), (2, f64::INFINITY),
+                            (3, f64::INFINITY)]);
+
+        // Minimizing scissors instead puts G with the fewest scissors in the
+        // first layer next to A, which beats all others
+        let layers = pareto_layers(&scores, "SFBs,scissors", &score_name_map());
+        assert_eq!(layers.iter().map(|&(l, _)| l).collect::<Vec<_>>(),
+                   [1, 2, 3, 2, 2, 1]);
+    }
+
+    #[test]
+    fn filter_operators() {
+        // "<=" and ">=" must not be parsed as "<" or ">" followed by "="
+        assert!(filter("SFBs<=8").unwrap().matches(&[8.0, 0.0, 1.0]));
+        assert!(!filter("SFBs<8").unwrap().matches(&[8.0, 0.0, 1.0]));
+        assert!(filter("SFBs>=8").unwrap().matches(&[8.0, 0.0, 1.0]));
+        assert!(!filter("SFBs>8").unwrap().matches(&[8.0, 0.0, 1.0]));
+        assert!(filter("SFBs==8").unwrap().matches(&[8.0, 0.0, 1.0]));
+        assert!(!filter("SFBs!=8").unwrap().matches(&[8.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn filter_precedence() {
+        // && binds tighter than ||: SFBs<1 || (scissors<1 && popularity>2)
+        let f = filter("SFBs<1 || scissors<1 && popularity>2").unwrap();
+        assert!(f.matches(&[0.0, 5.0, 0.0]));
+        assert!(f.matches(&[5.0, 0.0, 3.0]));
+        assert!(!f.matches(&[5.0, 0.0, 1.0]));
+        assert!(!f.matches(&[5.0, 5.0, 3.0]));
+
+        // (SFBs<1 && scissors<1) || popularity>2
+        let f = filter("SFBs<1 && scissors<1 || popularity>2").unwrap();
+        assert!(f.matches(&[5.0, 5.0, 3.0]));
+        assert!(!f.matches(&[0.0, 5.0, 1.0]));
+    }
+
+    #[test]
+    fn filter_whitespace_and_negative_numbers() {
+        let f = filter("  SFBs >= -1.5 &&scissors< -0.25  ||popularity  == 2 ").unwrap();
+        assert!(f.matches(&[-1.5, -0.5, 0.0]));
+        assert!(!f.matches(&[-2.0, -0.5, 0.0]));
+        assert!(!f.matches(&[0.0, 0.0, 0.0]));
+        assert!(f.matches(&[-9.0, 9.0, 2.0]));
+        assert!(filter("SFBs>-1").unwrap().matches(&[0.0, 0.0, 0.0]));
+    }
+
+    // Unknown names are reported with the list of valid names
+    #[test]
+    fn filter_unknown_name() {
+        assert_eq!(filter("SFBs<8 && SFB<8").err().as_deref(),
+                   Some("Unknown score name SFB. Valid names are:\n  \
+                         SFBs\n  popularity\n  scissors"));
+    }
+
+    #[test]
+    fn filter_malformed() {
+        for expr in ["SFBs", "SFBs<", "SFBs<abc", "SFBs=8", "SFBs=<8",
+                     "SFBs<<8", "<8", "SFBs<8 &&", "|| SFBs<8", "SFBs<8 | scissors<1"] {
+            assert!(filter(expr).is_err(), "{}", expr);
+        }
+        assert_eq!(filter("SFBs ~ 8").err().as_deref(),
+                   Some("Invalid filter condition 'SFBs ~ 8'. Expected <score><op><number> \
+                         with <op> one of <, <=, >, >=, ==, !="));
+    }
+
+    // Lines of the edited config that differ from the original, as
+    // (original, edited), with None for added or removed lines
+    fn changed_lines<'a>(a: &'a str, b: &'a str)
+        -> Vec<(Option<&'a str>, Option<&'a str>)>
+    {
+        let (a, b): (Vec<_>, Vec<_>) = (a.lines().collect(), b.lines().collect());
+        let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+        let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev())
+                                .take_while(|(x, y)| x == y).count();
+        let (a, b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+        (0..a.len().max(b.len())).map(|i| (a.get(i).copied(), b.get(i).copied()))
+                                 .collect()
+    }
+
+    fn edit(text: &str, name: &str, value: Option<&str>) -> Result<String, String> {
+        let mut doc: toml_edit::Document = text.parse().unwrap();
+        edit_config(&mut doc, name, value).map(|_| doc.to_string())
+    }
+
+    // Only the changed setting differs, comments and order are kept
+    #[test]
+    fn edit_config_keeps_other_lines() {
+        let edited = edit(EFFORT_CONFIG, "weights.SFBs", Some("12")).unwrap();
+        assert_eq!(changed_lines(EFFORT_CONFIG, &edited),
+                   [(Some("SFBs = 0"), Some("SFBs = 12"))]);
+
+        let edited = edit(EFFORT_CONFIG, "weights.travel", None).unwrap();
+        assert_eq!(changed_lines(EFFORT_CONFIG, &edited),
+                   [(Some("travel = 0"), None)]);
+
+        let edited = edit(EFFORT_CONFIG, "board_type", Some("ANSI")).unwrap();
+        assert!(edited.starts_with("board_type = \"ANSI\"\n"));
+        assert!(edited.ends_with(EFFORT_CONFIG));
+
+        let edited = edit(EFFORT_CONFIG, "targets.travel", Some("1.5")).unwrap();
+        assert!(edited.starts_with(EFFORT_CONFIG));
+        assert!(edited.ends_with("[targets]\ntravel = 1.5\n"));
+
+        // Setting a value and removing it again gives back the original
+        let edited = edit(EFFORT_CONFIG, "constraints.zxcv", Some("0.2")).unwrap();
+        assert_eq!(edit(&edited, "constraints.zxcv", None).unwrap(),
+                   EFFORT_CONFIG);
+
+        let text = "[weights]\nSFBs = 10.0 # keep this\n";
+        assert_eq!(edit(text, "weights.SFBs", Some("12")).unwrap(),
+                   "[weights]\nSFBs = 12 # keep this\n");
+    }
+
+    // A corpus mix is an array of tables that must come after the plain
+    // settings. Defaults are filled in.
+    #[test]
+    fn show_config_with_corpus_mix() {
+        let config: Config = toml::from_str("\
+            corpus = [{ path = 'a.json', weight = 0.7 },
+                      { path = 'b.json', weight = 0.3 }]
+            board_type = 'ANSI'
+            [weights]
+            SFBs = 12
+        ").unwrap();
+        let text = config_to_string(&config).unwrap();
+        let shown: toml::Value = toml::from_str(&text).unwrap();
+        assert_eq!(shown["board_type"].as_str(), Some("ANSI"));
+        assert_eq!(shown["corpus"][1]["weight"].as_float(), Some(0.3));
+        assert_eq!(shown["weights"]["SFBs"].as_float(), Some(12.0));
+        assert_eq!(shown["weights"]["scissors"].as_float(), Some(10.0));
+        assert!(text.find("board_type").unwrap() < text.find("[[corpus]]").unwrap());
+    }
+
+    #[test]
+    fn edit_config_errors() {
+        assert_eq!(edit(EFFORT_CONFIG, "targets.travel", None).unwrap_err(),
+                   "Setting 'targets.travel' is not in the configuration");
+        assert_eq!(edit(EFFORT_CONFIG, "weights.SFBs.x", Some("1")).unwrap_err(),
+                   "Invalid setting name 'weights.SFBs.x'");
+        assert_eq!(edit(EFFORT_CONFIG, "weights.", Some("1")).unwrap_err(),
+                   "Invalid setting name 'weights.'");
+    }
+}
@@ -0,0 +1,309 @@
+use super::{EvalScores, KuehlmakScores, layout_to_str};
+use super::eval::{KeyUsage, KeyPairs, Hand, FINGER_NAMES, heat_buckets};
+use super::export::key_name;
+use serde_json::{json, Value};
+use std::fmt::Write;
+
+// Heat map colors from the coldest to the hottest keys, matching the
+// terminal colors, with a readable font color for each
+const HEAT_FILL: [(&str, &str); 6] = [
+    ("#2c5aa0", "#ffffff"), ("#2ca0b4", "#000000"), ("#a8a8a8", "#000000"),
+    ("#b040b0", "#ffffff"), ("#d03c3c", "#ffffff"), ("#e8c830", "#000000"),
+];
+const UNUSED_FILL: (&str, &str) = ("#eeeeee", "#000000");
+// Space is much more frequent and cheaper than any other key, so it's not
+// part of the buckets and gets its own color
+const SPACE_FILL: (&str, &str) = ("#ffffff", "#000000");
+
+// Colors of the arrows for SFBs and scissors
+const ARROW_COLORS: [&str; 2] = ["#000000", "#00a040"];
+
+// Size of a key in pixels
+const KEY_SIZE: f32 = 48.0;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+     .replace('"', "&quot;")
+}
+
+fn symbols_label(symbols: [char; 2]) -> String {
+    match symbols {
+        [' ', _] => "␣".to_string(),
+        symbols => key_name(symbols),
+    }
+}
+
+// What the key colors in an SVG image represent
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyColors {
+    // Share of keystrokes, like the heat map
+    Heat,
+    // Effort cost of each key
+    Cost,
+}
+
+fn is_space(key: &KeyUsage) -> bool {
+    key.symbols[0] == ' '
+}
+
+// Buckets for coloring keys by cost, evenly spaced between the cheapest
+// and the most expensive key other than Space
+fn cost_buckets(keys: &[KeyUsage]) -> Vec<Option<usize>> {
+    let costs = || keys.iter().filter(|k| !is_space(k)).map(|k| k.cost);
+    let min = costs().min().unwrap_or(0);
+    let max = costs().max().unwrap_or(0);
+    keys.iter().map(|k| (!is_space(k)).then(||
+        (k.cost - min) as usize * HEAT_FILL.len()
+            / (max - min + 1) as usize)).collect()
+}
+
+// Keys at their physical positions, colored by heat map buckets or cost,
+// with optional arrows for frequent SFBs and scissors
+fn keyboard_svg(keys: &[KeyUsage], colors: KeyColors,
+                arrows: &[(&str, KeyPairs)]) -> String {
+    let buckets = match colors {
+        KeyColors::Heat => {
+            let usage: Vec<_> = keys.iter()
+                .map(|k| if is_space(k) {0.0} else {k.usage})
+                .collect();
+            heat_buckets(&usage)
+        },
+        KeyColors::Cost => cost_buckets(keys),
+    };
+    let min_x = keys.iter().map(|k| k.x).fold(f32::INFINITY, f32::min);
+    let max_x = keys.iter().map(|k| k.x).fold(f32::NEG_INFINITY, f32::max);
+    let min_y = keys.iter().map(|k| k.y).fold(f32::INFINITY, f32::min);
+    let max_y = keys.iter().map(|k| k.y).fold(f32::NEG_INFINITY, f32::max);
+    let center = |key: usize| keys.iter().find(|k| k.key == key).map(|k|
+        ((k.x - min_x + 0.5) * KEY_SIZE, (k.y - min_y + 0.5) * KEY_SIZE));
+
+    let mut svg = String::new();
+    writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{:.0}" height="{:.0}" font-family="sans-serif" text-anchor="middle">"#,
+             (max_x - min_x + 1.0) * KEY_SIZE,
+             (max_y - min_y + 1.0) * KEY_SIZE).unwrap();
+    for (key, bucket) in keys.iter().zip(buckets) {
+        let (fill, text) = if is_space(key) {SPACE_FILL}
+                           else {bucket.map_or(UNUSED_FILL, |b| HEAT_FILL[b])};
+        let x = (key.x - min_x) * KEY_SIZE;
+        let y = (key.y - min_y) * KEY_SIZE;
+        writeln!(svg, r##"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" rx="6" fill="{}" stroke="#555555"><title>usage {:.1}, cost {}</title></rect>"##,
+                 x + 2.0, y + 2.0, KEY_SIZE - 4.0, KEY_SIZE - 4.0, fill,
+                 key.usage, key.cost).unwrap();
+        writeln!(svg, r#"<text x="{:.1}" y="{:.1}" font-size="16" fill="{}">{}</text>"#,
+                 x + KEY_SIZE / 2.0, y + KEY_SIZE / 2.0, text,
+                 escape(&symbols_label(key.symbols))).unwrap();
+        let value = match colors {
+            KeyColors::Heat => format!("{:.0}", key.usage),
+            KeyColors::Cost => format!("{}", key.cost),
+        };
+        writeln!(svg, r#"<text x="{:.1}" y="{:.1}" font-size="11" fill="{}">{}</text>"#,
+                 x + KEY_SIZE / 2.0, y + KEY_SIZE - 8.0, text,
+                 value).unwrap();
+    }
+
+    // Arrow widths are relative to the most frequent bigram, so they're
+    // comparable between SFBs and scissors
+    let max_freq = arrows.iter().flat_map(|(_, list)| list)
+                         .map(|&(_, _, p)| p).fold(0.0, f64::max);
+    for (i, (name, list)) in arrows.iter().enumerate() {
+        let color = ARROW_COLORS[i % ARROW_COLORS.len()];
+        if list.is_empty() {
+            continue;
+        }
+        writeln!(svg, r#"<defs><marker id="arrow-{}" viewBox="0 0 10 10" refX="8" refY="5" markerWidth="4" markerHeight="4" orient="auto-start-reverse"><path d="M 0 0 L 10 5 L 0 10 z" fill="{}"/></marker></defs>"#,
+                 i, color).unwrap();
+        for &(a, b, p) in list.iter().filter(|&&(a, b, _)| a != b) {
+            let (Some((x1, y1)), Some((x2, y2))) = (center(a), center(b))
+                else {continue};
+            // Stop short of the key centers to keep the labels readable and
+            // move arrows to the side, so opposite directions don't overlap
+            let (dx, dy) = (x2 - x1, y2 - y1);
+            let len = (dx * dx + dy * dy).sqrt();
+            let short = (KEY_SIZE * 0.3 / len).min(0.4);
+            let (sx, sy) = (-dy / len * 4.0, dx / len * 4.0);
+            writeln!(svg, r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="{}" stroke-width="{:.1}" stroke-opacity="0.8" marker-end="url(#arrow-{})"><title>{} {:.2}</title></line>"#,
+                     x1 + dx * short + sx, y1 + dy * short + sy,
+                     x2 - dx * short + sx, y2 - dy * short + sy, color,
+                     1.5 + 4.5 * (p / max_freq) as f32, i,
+                     escape(name), p).unwrap();
+        }
+    }
+    writeln!(svg, "</svg>").unwrap();
+    svg
+}
+
+fn write_table<I>(html: &mut String, class: &str, header: &[&str], rows: I)
+    where I: IntoIterator<Item = Vec<String>>
+{
+    writeln!(html, r#"<table class="{}"><thead><tr>"#, class).unwrap();
+    for h in header {
+        writeln!(html, "<th>{}</th>", h).unwrap();
+    }
+    writeln!(html, "</tr></thead><tbody>").unwrap();
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            write!(html, "<td>{}</td>", cell).unwrap();
+        }
+        html.push_str("</tr>\n");
+    }
+    writeln!(html, "</tbody></table>").unwrap();
+}
+
+fn write_layout(html: &mut String, heading: &str, scores: &KuehlmakScores) {
+    writeln!(html, "<section>\n<h2>{}</h2>", escape(heading)).unwrap();
+    writeln!(html, r#"<div class="overview">"#).unwrap();
+    html.push_str(&keyboard_svg(&scores.key_usage(), KeyColors::Heat, &[]));
+
+    // Scores in the same order as get_scores
+    let mut names: Vec<_> = KuehlmakScores::get_score_names().into_iter()
+                                                          .collect();
+    names.sort_by_key(|&(_, i)| i);
+    let values = scores.get_scores();
+    write_table(html, "scores", &["Score", "Value"],
+                names.into_iter().map(|(name, i)|
+                    vec![name, format!("{:.1}", values[i])]));
+    writeln!(html, "</div>").unwrap();
+
+    writeln!(html, "<h3>Fingers</h3>").unwrap();
+    write_table(html, "sortable", &["Finger", "Usage", "Effort", "Travel"],
+                FINGER_NAMES.iter().zip(scores.finger_usage())
+                            .map(|(name, (usage, effort, travel))| vec![
+                    name.to_string(), format!("{:.1}", usage),
+                    format!("{:.1}", effort), format!("{:.1}", travel)]));
+
+    // Like the text output, n-grams below 0.005 per 1000 keystrokes are
+    // left out
+    let lists = scores.ngram_lists();
+    if !lists.is_empty() {
+        writeln!(html, "<h3>N-grams</h3>").unwrap();
+    }
+    for (name, list) in lists {
+        let total: f64 = list.iter().map(|&(_, _, p)| p).sum();
+        writeln!(html, "<details><summary>{} ({:.2})</summary>",
+                 escape(name), total).unwrap();
+        write_table(html, "sortable", &["N-gram", "Hand", "Frequency"],
+                    list.into_iter().filter(|&(_, _, p)| p >= 0.005)
+                        .map(|(ngram, hand, p)| vec![
+                            format!("<code>{}</code>", escape(&ngram)),
+                            match hand {Hand::L => "Left", Hand::R => "Right",
+                                        Hand::Any => ""}.to_string(),
+                            format!("{:.2}", p)]));
+        writeln!(html, "</details>").unwrap();
+    }
+    writeln!(html, "</section>").unwrap();
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+section { margin-bottom: 3em; }
+.overview { display: flex; gap: 2em; align-items: flex-start; flex-wrap: wrap; }
+table { border-collapse: collapse; margin: 0.5em 0; }
+th, td { padding: 0.2em 0.8em; border-bottom: 1px solid #ddd; }
+td:not(:first-child) { text-align: right; }
+table.sortable th { cursor: pointer; }
+code { white-space: pre; }
+";
+
+// Clicking on a column header sorts the table by that column
+const SCRIPT: &str = "
+document.querySelectorAll('table.sortable th').forEach(th => {
+  th.addEventListener('click', () => {
+    const body = th.closest('table').tBodies[0];
+    const i = th.cellIndex;
+    const asc = th.dataset.dir !== 'asc';
+    th.dataset.dir = asc ? 'asc' : 'desc';
+    const value = row => {
+      const text = row.cells[i].textContent;
+      return i > 0 && text !== '' && !isNaN(text) ? parseFloat(text) : text;
+    };
+    Array.from(body.rows).sort((a, b) => {
+      const x = value(a), y = value(b);
+      return (x < y ? -1 : x > y ? 1 : 0) * (asc ? 1 : -1);
+    }).forEach(row => body.appendChild(row));
+  });
+});
+";
+
+// Self-contained HTML page with a section for each layout
+pub fn scores_to_html(title: &str, layouts: &[(String, KuehlmakScores)])
+    -> String
+{
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">").unwrap();
+    writeln!(html, "<title>{}</title>", escape(title)).unwrap();
+    writeln!(html, "<style>{}</style>\n</head>\n<body>", STYLE).unwrap();
+    writeln!(html, "<h1>{}</h1>", escape(title)).unwrap();
+    for (heading, scores) in layouts {
+        write_layout(&mut html, heading, scores);
+    }
+    writeln!(html, "<script>{}</script>\n</body>\n</html>", SCRIPT).unwrap();
+    html
+}
+
+// Standalone SVG image of a layout with arrows for the most frequent SFBs
+// and scissors. Arrows need the layout to be evaluated with extra lists.
+pub fn scores_to_svg(scores: &KuehlmakScores, colors: KeyColors,
+                     arrows: usize) -> String {
+    let arrows = if arrows > 0 {
+        vec![("SFB", scores.sfb_keys(arrows)),
+             ("Scissor", scores.scissor_keys(arrows))]
+    } else {
+        Vec::new()
+    };
+    let mut svg = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    svg.push('\n');
+    svg.push_str(&keyboard_svg(&scores.key_usage(), colors, &arrows));
+    svg
+}
+
+// Version of the JSON output format. It changes when fields are removed or
+// their meaning changes. Adding fields doesn't change the version.
+pub const JSON_VERSION: u32 = 1;
+
+// JSON object with the layout, all named scores, the heat map, per-finger
+// stats, hand runs and, if the layout was evaluated with extra lists, the
+// n-gram lists. See the README for a description of the fields.
+pub fn scores_to_json(scores: &KuehlmakScores) -> Value {
+    let values = scores.get_scores();
+    let named: serde_json::Map<_, _> = KuehlmakScores::get_score_names()
+        .into_iter().map(|(name, i)| (name, json!(values[i]))).collect();
+    let keys: Vec<_> = scores.key_usage().into_iter().map(|k| json!({
+        "key": k.key,
+        "symbols": k.symbols.iter().filter(|&&c| c != '\0')
+                            .collect::<String>(),
+        "x": k.x,
+        "y": k.y,
+        "usage": k.usage,
+        "cost": k.cost,
+    })).collect();
+    let fingers: Vec<_> = FINGER_NAMES.iter().zip(scores.finger_usage())
+        .map(|(name, (usage, effort, travel))| json!({
+            "finger": name,
+            "usage": usage,
+            "effort": effort,
+            "travel": travel,
+        })).collect();
+
+    let mut json = json!({
+        "layout": layout_to_str(&scores.layout()),
+        "scores": named,
+        "heatmap": keys,
+        "fingers": fingers,
+        "hand_runs": scores.hand_runs(),
+    });
+    let lists = scores.ngram_lists();
+    if !lists.is_empty() {
+        // Category names without explanations, e.g. "LSB3s"
+        json["ngrams"] = lists.into_iter().map(|(name, list)| (
+            name.split(' ').next().unwrap().to_string(),
+            list.into_iter().map(|(ngram, hand, p)| json!({
+                "ngram": ngram,
+                "hand": match hand {Hand::L => Some("L"), Hand::R => Some("R"),
+                                    Hand::Any => None},
+                "frequency": p,
+            })).collect::<Value>()
+        )).collect::<serde_json::Map<_, _>>().into();
+    }
+    json
+}
@@ -14,6 +14,9 @@ pub type Symbol = [char; 1];
 pub type Bigram = [char; 2];
 pub type Trigram = [char; 3];
 
+// Symbol inserted before runs of capitalized letters in shift-aware corpora
+pub const SHIFT: char = '⇧';
+
 #[derive(Clone, Debug)]
 struct NGramStats<T> {
     map: MyMap<T, (u64, usize)>,    // n-Gram counters+tokens in a hashmap
@@ -56,6 +59,9 @@ impl<T: Copy + IntoIterator<Item = char>> SerializeTrait for NGramStats<T> {
     }
 }
 
+// Position in the sorted n-gram list, count and symbol tokens of an n-gram
+pub type IndexedNGram = (usize, u64, [usize; 3]);
+
 #[derive(Deserialize)]
 struct TextMaps {
     symbols: MyMap<String, u64>,
@@ -76,6 +82,10 @@ pub struct TextStats {
     token_base: usize,
     #[serde(skip)]
     token_map: Vec<u64>,
+    #[serde(skip)]
+    symbol_bigrams: Vec<Vec<IndexedNGram>>,
+    #[serde(skip)]
+    symbol_trigrams: Vec<Vec<IndexedNGram>>,
 }
 
 impl TryFrom<TextMaps> for TextStats {
@@ -120,6 +130,15 @@ impl FromStr for TextStats {
     type Err = &'static str;
 
     fn from_str(text: &str) -> Result <Self, Self::Err> {
+        Self::from_text(text, false)
+    }
+}
+
+impl TextStats {
+    // With shift == true, a SHIFT symbol is inserted before each run of
+    // capitalized letters. That turns bigrams like "lU" and " U" into
+    // 3-grams "l⇧u" and " ⇧u", so that Shift gets counted as a keystroke.
+    pub fn from_text(text: &str, shift: bool) -> Result <Self, &'static str> {
         let len = text.chars().count();
         let mut i = 0usize;
         let mut bigram = ['\0'; 2];
@@ -127,6 +146,8 @@ impl FromStr for TextStats {
         let mut s_map = MyMap::new();
         let mut b_map = MyMap::new();
         let mut t_map = MyMap::new();
+        let mut in_caps = false;
+        let mut symbols = Vec::new();
 
         // Build maps of symbols, bigrams and 3-grams of lower-case
         // characters in the text. Collapse all consecutive whitespace
@@ -141,7 +162,15 @@ impl FromStr for TextStats {
                 continue;
             }
 
-            for c in c.to_lowercase() {
+            symbols.clear();
+            let is_caps = shift && c.is_uppercase();
+            if is_caps && !in_caps {
+                symbols.push(SHIFT);
+            }
+            in_caps = is_caps;
+            symbols.extend(c.to_lowercase());
+
+            for &c in symbols.iter() {
                 let symbol = [c];
                 trigram[0..2].copy_from_slice(&bigram[..]);
                 trigram[2] = c;
@@ -165,9 +194,7 @@ impl FromStr for TextStats {
 
         Self::from_maps(s_map, b_map, t_map)
     }
-}
 
-impl TextStats {
     fn from_maps(mut s_map: MyMap<Symbol, (u64, usize)>,
                  mut b_map: MyMap<Bigram, (u64, usize)>,
                  mut t_map: MyMap<Trigram, (u64, usize)>)
@@ -215,12 +242,42 @@ impl TextStats {
             }
         }
 
+        let b = NGramStats::from_map(b_map);
+        let t = NGramStats::from_map(t_map);
+
+        // Index the n-grams containing each symbol, so they can be
+        // revisited quickly when a symbol moves
+        fn index_ngrams<I>(ngrams: I, token_base: usize)
+            -> Vec<Vec<IndexedNGram>>
+            where I: Iterator<Item = (u64, usize)>
+        {
+            let mut index = vec![Vec::new(); token_base];
+            for (i, (count, mut token)) in ngrams.enumerate() {
+                let mut symbols = [0; 3];
+                for s in symbols.iter_mut() {
+                    *s = token % token_base;
+                    token /= token_base;
+                }
+                for (j, &s) in symbols.iter().enumerate() {
+                    if s != 0 && !symbols[..j].contains(&s) {
+                        index[s].push((i, count, symbols));
+                    }
+                }
+            }
+            index
+        }
+        let symbol_bigrams = index_ngrams(
+            b.list.iter().map(|&(_, count, token)| (count, token)), token_base);
+        let symbol_trigrams = index_ngrams(
+            t.list.iter().map(|&(_, count, token)| (count, token)), token_base);
+
         Ok(TextStats {
             s: NGramStats::from_map(s_map),
-            b: NGramStats::from_map(b_map),
-            t: NGramStats::from_map(t_map),
+            b, t,
             token_base,
             token_map,
+            symbol_bigrams,
+            symbol_trigrams,
         })
     }
 }
@@ -280,6 +337,44 @@ impl TextStats {
         Self::from_maps(s_map, b_map, t_map).unwrap()
     }
 
+    // Mix several corpora with the given weights. The symbol, bigram and
+    // 3-gram counts of each corpus are rescaled to a common total before
+    // merging them, so the weights don't depend on the corpus sizes. The
+    // common total is the largest one of all corpora to preserve precision.
+    pub fn mix<'a, I>(corpora: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a TextStats, f64)>
+    {
+        let corpora: Vec<_> = corpora.into_iter().collect();
+        let weight_sum: f64 = corpora.iter().map(|&(_, w)| w).sum();
+
+        fn mix_ngrams<T: Ord + Copy>(corpora: &[(&NGramStats<T>, f64)],
+                                     weight_sum: f64, keep_zero: bool)
+            -> MyMap<T, (u64, usize)>
+        {
+            let norm = corpora.iter().map(|(ngrams, _)| ngrams.total)
+                              .max().unwrap_or(0) as f64 / weight_sum;
+            let mut mix = MyMap::new();
+            for &(ngrams, weight) in corpora.iter() {
+                let scale = weight * norm / ngrams.total.max(1) as f64;
+                for &(ngram, count, _) in ngrams.iter() {
+                    *mix.entry(ngram).or_insert(0.0) += count as f64 * scale;
+                }
+            }
+            mix.into_iter().map(|(ngram, count)| (ngram, (count.round() as u64, 0)))
+               .filter(|&(_, (count, _))| keep_zero || count > 0)
+               .collect()
+        }
+
+        // Keep all symbols so that rare n-grams don't lose their symbols
+        let s: Vec<_> = corpora.iter().map(|&(ts, w)| (&ts.s, w)).collect();
+        let b: Vec<_> = corpora.iter().map(|&(ts, w)| (&ts.b, w)).collect();
+        let t: Vec<_> = corpora.iter().map(|&(ts, w)| (&ts.t, w)).collect();
+        Self::from_maps(mix_ngrams(&s, weight_sum, true),
+                        mix_ngrams(&b, weight_sum, false),
+                        mix_ngrams(&t, weight_sum, false)).unwrap()
+    }
+
     pub fn iter_symbols(&self)
         -> std::slice::Iter<(Symbol, u64, usize)> {self.s.iter()}
     pub fn iter_bigrams(&self)
@@ -297,6 +392,15 @@ impl TextStats {
         self.t.map.get(&index)
     }
 
+    // N-grams containing a symbol token, in the order of iter_bigrams
+    // and iter_trigrams
+    pub fn symbol_bigrams(&self, token: usize) -> &[IndexedNGram] {
+        &self.symbol_bigrams[token]
+    }
+    pub fn symbol_trigrams(&self, token: usize) -> &[IndexedNGram] {
+        &self.symbol_trigrams[token]
+    }
+
     pub fn total_symbols(&self) -> u64 {self.s.total}
     pub fn total_bigrams(&self) -> u64 {self.b.total}
     pub fn total_trigrams(&self) -> u64 {self.t.total}
@@ -586,4 +690,38 @@ mod tests {
             }
         }
     }
+
+    // Check that Shift is inserted once before each run of capitals
+    #[test]
+    fn shift() {
+        let stats = TextStats::from_text("Hello, WORLD! McD", true).unwrap();
+
+        assert_eq!(stats.get_symbol([SHIFT]).unwrap().0, 4);
+        assert_eq!(stats.get_symbol(['h']).unwrap().0, 1);
+        assert_eq!(stats.get_bigram([' ', SHIFT]).unwrap().0, 2);
+        assert_eq!(stats.get_bigram([SHIFT, 'w']).unwrap().0, 1);
+        assert_eq!(stats.get_trigram(['c', SHIFT, 'd']).unwrap().0, 1);
+        assert_eq!(stats.get_bigram(['c', 'd']), None);
+        assert_eq!(stats.get_bigram([SHIFT, 'o']), None);
+
+        let stats = TextStats::from_text("Hello, WORLD! McD", false).unwrap();
+        assert_eq!(stats.get_symbol([SHIFT]), None);
+    }
+
+    // Check that mixed corpora are normalized before applying the weights
+    #[test]
+    fn mix() {
+        let a = TextStats::from_str("aaab").unwrap();
+        let b = TextStats::from_str("cdcdcdcdcdcdcdcd").unwrap();
+        let mix = TextStats::mix([(&a, 0.75), (&b, 0.25)]);
+
+        assert_eq!(mix.total_symbols(), b.total_symbols());
+        assert_eq!(mix[['a']].0, 9);
+        assert_eq!(mix[['b']].0, 3);
+        assert_eq!(mix[['c']].0, 2);
+        assert_eq!(mix[['d']].0, 2);
+        assert_eq!(mix[['a', 'a']].0, 8);
+        assert_eq!(mix[['d', 'c']].0, 2);
+        assert_eq!(mix.get_trigram(['c', 'd', 'c']).unwrap().0, 2);
+    }
 }